@@ -0,0 +1,33 @@
+// Compares processing deposits and withdrawals arriving in long bursts for
+// a single client with the same rows for alternating clients, which shows how
+// much client lookups cost on locality-heavy inputs.
+// Run with `cargo bench --bench client_bursts`.
+use std::time::Instant;
+use transactions::TransactionEngine;
+
+const ROWS: u32 = 1_000_000;
+
+fn input(clients: u32) -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=ROWS {
+        let op = if tx % 4 == 0 { "withdrawal" } else { "deposit" };
+        input.push_str(&format!("{},{},{},1.5\n", op, tx % clients + 1, tx));
+    }
+    input
+}
+
+fn run(name: &str, input: &str) {
+    let reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let start = Instant::now();
+    let mut engine = TransactionEngine::new();
+    engine.apply_csv_reader(reader).unwrap();
+    println!("{:>11}, {} rows: {:?}", name, ROWS, start.elapsed());
+}
+
+fn main() {
+    run("burst", &input(1));
+    run("alternating", &input(2));
+}
@@ -0,0 +1,57 @@
+// Compares keeping clients in a hash map with a flat vector indexed by client
+// id, for rows spread over ~60k clients, so that client lookups dominate.
+// Run with `cargo bench --bench client_store`.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use transactions::TransactionEngine;
+
+const ROWS: u32 = 200_000;
+const CLIENTS: u32 = 60_000;
+
+fn input() -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=ROWS {
+        let op = if tx % 4 == 0 { "withdrawal" } else { "deposit" };
+        // Strided, so that consecutive rows hit distant clients
+        let client = tx.wrapping_mul(7919) % CLIENTS + 1;
+        input.push_str(&format!("{},{},{},1.5\n", op, client, tx));
+    }
+    input
+}
+
+fn apply(input: &str, mut engine: TransactionEngine) -> TransactionEngine {
+    let reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    engine
+}
+
+fn client_store(c: &mut Criterion) {
+    let input = input();
+    let mut group = c.benchmark_group("client_store");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(ROWS.into()));
+    group.bench_function("map", |b| {
+        b.iter_batched(
+            || TransactionEngine::new().with_disputes_enabled(false),
+            |engine| apply(&input, engine),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("dense", |b| {
+        b.iter_batched(
+            || {
+                TransactionEngine::new()
+                    .with_dense_client_store()
+                    .with_disputes_enabled(false)
+            },
+            |engine| apply(&input, engine),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, client_store);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+// Compares processing a deposit/withdrawal-only input with disputes enabled
+// and disabled. Run with `cargo bench --bench disputes_disabled`.
+use std::time::Instant;
+use transactions::TransactionEngine;
+
+const ROWS: u32 = 1_000_000;
+
+fn input() -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=ROWS {
+        let op = if tx % 4 == 0 { "withdrawal" } else { "deposit" };
+        input.push_str(&format!("{},{},{},1.5\n", op, (tx - 1) / 4 % 1000 + 1, tx));
+    }
+    input
+}
+
+fn run(input: &str, disputes_enabled: bool) {
+    let reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let start = Instant::now();
+    let mut engine = TransactionEngine::new().with_disputes_enabled(disputes_enabled);
+    engine.apply_csv_reader(reader).unwrap();
+    println!(
+        "disputes enabled: {:5}, {} rows: {:?}",
+        disputes_enabled,
+        ROWS,
+        start.elapsed()
+    );
+}
+
+fn main() {
+    let input = input();
+    run(&input, true);
+    run(&input, false);
+}
@@ -0,0 +1,41 @@
+// Compares reading a large CSV file through input buffers of different sizes.
+// The effect grows with the latency of each read, so it's worth running on
+// the storage in question, e.g. a network mount via `TRANSACTIONS_BENCH_DIR`.
+// Run with `cargo bench --bench input_buffer`.
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+use transactions::TransactionEngine;
+
+const ROWS: u32 = 1_000_000;
+
+fn write_input(path: &Path) {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+    writeln!(file, "type,client,tx,amount").unwrap();
+    for tx in 1..=ROWS {
+        let op = if tx % 4 == 0 { "withdrawal" } else { "deposit" };
+        writeln!(file, "{},{},{},1.5", op, tx % 1000 + 1, tx).unwrap();
+    }
+}
+
+fn run(path: &Path, buffer_size: Option<usize>) {
+    let mut engine = TransactionEngine::new().with_disputes_enabled(false);
+    if let Some(bytes) = buffer_size {
+        engine = engine.with_input_buffer_size(bytes);
+    }
+    let start = Instant::now();
+    engine.apply_csv_path(path.to_str().unwrap()).unwrap();
+    let name = buffer_size.map_or_else(|| "default".to_string(), |bytes| bytes.to_string());
+    println!("{:>8} bytes, {} rows: {:?}", name, ROWS, start.elapsed());
+}
+
+fn main() {
+    let dir =
+        std::env::var_os("TRANSACTIONS_BENCH_DIR").map_or_else(std::env::temp_dir, Into::into);
+    let path = dir.join(format!("transactions-input-{}.csv", std::process::id()));
+    write_input(&path);
+    for buffer_size in [None, Some(1 << 10), Some(1 << 16), Some(1 << 20)] {
+        run(&path, buffer_size);
+    }
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,38 @@
+// Measures writing balances of the maximum number of clients into a file,
+// as is and wrapped in a `BufWriter`, which shows whether output is buffered
+// well enough internally. Run with `cargo bench --bench output`.
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::Instant;
+use transactions::{ClientId, Operation, TransactionEngine};
+
+fn run(name: &str, engine: &TransactionEngine, buffered: bool) {
+    let path = std::env::temp_dir().join(format!("transactions-output-{}", std::process::id()));
+    let file = File::create(&path).unwrap();
+    let start = Instant::now();
+    if buffered {
+        engine.to_csv_writer(BufWriter::new(file)).unwrap();
+    } else {
+        engine.to_csv_writer(file).unwrap();
+    }
+    println!(
+        "{:>8}, {} clients: {:?}",
+        name,
+        engine.clients().len(),
+        start.elapsed()
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+fn main() {
+    let mut engine = TransactionEngine::new();
+    for client in 1..=ClientId::MAX {
+        engine.apply(Operation::Deposit {
+            tx: client.into(),
+            client,
+            amount: 1.into(),
+        });
+    }
+    run("file", &engine, false);
+    run("buffered", &engine, true);
+}
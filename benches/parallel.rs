@@ -0,0 +1,34 @@
+// Compares applying rows serially with applying each client's rows on a rayon
+// thread pool, for inputs spread over many clients.
+// Run with `cargo bench --bench parallel --features rayon`.
+use std::time::Instant;
+use transactions::TransactionEngine;
+
+const ROWS: u32 = 1_000_000;
+const CLIENTS: u32 = 1_000;
+
+fn input() -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=ROWS {
+        let op = if tx % 4 == 0 { "withdrawal" } else { "deposit" };
+        input.push_str(&format!("{},{},{},1.5\n", op, tx % CLIENTS + 1, tx));
+    }
+    input
+}
+
+fn reader(input: &str) -> csv::Reader<&[u8]> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(input.as_bytes())
+}
+
+fn main() {
+    let input = input();
+    let start = Instant::now();
+    TransactionEngine::from_csv_reader(reader(&input)).unwrap();
+    println!("  serial, {} rows: {:?}", ROWS, start.elapsed());
+    let start = Instant::now();
+    TransactionEngine::from_csv_parallel(reader(&input)).unwrap();
+    println!("parallel, {} rows: {:?}", ROWS, start.elapsed());
+}
@@ -0,0 +1,7975 @@
+use core::str::FromStr;
+use csv::{ReaderBuilder, Trim};
+#[cfg(test)]
+use proptest::prelude::*;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc;
+
+pub type ClientId = u16;
+pub type TxId = u32;
+
+// Hash maps are seeded randomly by default. With the `fixed-hasher` feature,
+// they use a hasher with fixed keys instead, so that they iterate in the same
+// order in every run, e.g. for reproducing order-sensitive bugs
+#[cfg(not(feature = "fixed-hasher"))]
+type MapHasher = std::collections::hash_map::RandomState;
+#[cfg(feature = "fixed-hasher")]
+type MapHasher = std::hash::BuildHasherDefault<DefaultHasher>;
+
+type HashMap<K, V> = std::collections::HashMap<K, V, MapHasher>;
+type HashSet<T> = std::collections::HashSet<T, MapHasher>;
+
+// Amounts are specified with a precision of up to four places past the decimal
+const DECIMAL_PLACES: u32 = 4;
+// Columns of headerless input, in order
+const DEFAULT_HEADERS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+// Amounts are stored as `Decimal` by default. With the `minor-units-core` feature,
+// they're stored as integers in minor units instead, and converted from/to decimals
+// only when parsing input and printing output
+#[cfg(not(feature = "minor-units-core"))]
+pub type Amount = Decimal;
+#[cfg(feature = "minor-units-core")]
+pub type Amount = MinorUnits;
+
+#[cfg(not(feature = "minor-units-core"))]
+fn to_amount(value: Decimal) -> Result<Amount, String> {
+    Ok(value)
+}
+
+#[cfg(feature = "minor-units-core")]
+fn to_amount(value: Decimal) -> Result<Amount, String> {
+    MinorUnits::from_decimal(value)
+}
+
+// Amount as an integer number of 1/10000 units, rounded if it has more
+// than 4 decimal places
+#[cfg(not(feature = "minor-units-core"))]
+fn to_minor_units(amount: Amount) -> i128 {
+    let mut amount = amount;
+    amount.rescale(DECIMAL_PLACES);
+    amount.mantissa()
+}
+
+#[cfg(feature = "minor-units-core")]
+fn to_minor_units(amount: Amount) -> i128 {
+    amount.0
+}
+
+// Number of significant decimal places, ignoring trailing zeros
+#[cfg(not(feature = "minor-units-core"))]
+fn decimal_places(amount: Amount) -> u32 {
+    amount.normalize().scale()
+}
+
+// Minor units can't have more than 4 decimal places
+#[cfg(feature = "minor-units-core")]
+fn decimal_places(_amount: Amount) -> u32 {
+    DECIMAL_PLACES
+}
+
+#[cfg(not(feature = "minor-units-core"))]
+fn from_minor_units(units: i128) -> Amount {
+    Decimal::from_i128_with_scale(units, DECIMAL_PLACES)
+}
+
+#[cfg(feature = "minor-units-core")]
+fn from_minor_units(units: i128) -> Amount {
+    MinorUnits(units)
+}
+
+// Splits the amount into parts proportional to the weights, e.g. for fees
+// shared between sub-accounts, such that the parts sum up exactly to the amount
+// rounded to 4 decimal places. Shares are computed in 1/10000 units with the
+// largest remainder method: every part gets its share rounded down, and the
+// units left over go one each to the parts with the largest remainders, the
+// earlier part first on ties.
+pub fn split_by_weights(amount: Amount, weights: &[u32]) -> Result<Vec<Amount>, String> {
+    let total_weight: i128 = weights.iter().map(|&weight| i128::from(weight)).sum();
+    if total_weight == 0 {
+        return Err("Weights of a split must not all be zero".to_string());
+    }
+    let units = to_minor_units(amount);
+    let sign = units.signum();
+    let units = units.abs();
+    let mut shares: Vec<(i128, i128)> = weights
+        .iter()
+        .map(|&weight| {
+            let scaled = units * i128::from(weight);
+            (scaled / total_weight, scaled % total_weight)
+        })
+        .collect();
+    let leftover = units - shares.iter().map(|(share, _)| share).sum::<i128>();
+    let mut by_remainder: Vec<usize> = (0..shares.len()).collect();
+    by_remainder.sort_by_key(|&i| std::cmp::Reverse(shares[i].1));
+    for &i in by_remainder.iter().take(leftover as usize) {
+        shares[i].0 += 1;
+    }
+    Ok(shares
+        .into_iter()
+        .map(|(share, _)| from_minor_units(sign * share))
+        .collect())
+}
+
+// Amount as printed: rounded to at most 4 decimal places with banker's rounding
+// (half to even, `Decimal::round_dp`), so 5.12345 prints as 5.1234 and 5.12355
+// as 5.1236. Amounts with fewer places keep their scale, and zero of any scale
+// prints as 0.
+#[cfg(not(feature = "minor-units-core"))]
+fn round_output(amount: Amount) -> Amount {
+    let rounded = amount.round_dp(DECIMAL_PLACES);
+    if rounded.is_zero() {
+        Decimal::ZERO
+    } else {
+        rounded
+    }
+}
+
+#[cfg(feature = "minor-units-core")]
+fn round_output(amount: Amount) -> Amount {
+    amount
+}
+
+// Canonical zero for guards, with the same scale as input amounts. Comparisons
+// of decimals don't depend on their scale, so `0.into()` would work too, but
+// all guards use the same value to rule out any scale mismatch.
+#[cfg(not(feature = "minor-units-core"))]
+fn zero() -> Amount {
+    Decimal::new(0, DECIMAL_PLACES)
+}
+
+#[cfg(feature = "minor-units-core")]
+fn zero() -> Amount {
+    MinorUnits(0)
+}
+
+// Adds to a balance, returning false if the result had to be rounded, which
+// happens once it doesn't fit in Decimal's 28 significant digits
+#[cfg(not(feature = "minor-units-core"))]
+fn add_exact(balance: &mut Amount, amount: Amount) -> bool {
+    // Adding to or adding a zero may yield the other operand as is, with its own
+    // scale, but it's exact anyway
+    let scale = balance.scale().max(amount.scale());
+    let trivial = balance.is_zero() || amount.is_zero();
+    *balance += amount;
+    trivial || balance.scale() >= scale
+}
+
+#[cfg(not(feature = "minor-units-core"))]
+fn sub_exact(balance: &mut Amount, amount: Amount) -> bool {
+    add_exact(balance, -amount)
+}
+
+// Integer arithmetic is always exact
+#[cfg(feature = "minor-units-core")]
+fn add_exact(balance: &mut Amount, amount: Amount) -> bool {
+    *balance += amount;
+    true
+}
+
+#[cfg(feature = "minor-units-core")]
+fn sub_exact(balance: &mut Amount, amount: Amount) -> bool {
+    *balance -= amount;
+    true
+}
+
+// Sum of amounts, or None if it doesn't fit in the amount type
+#[cfg(not(feature = "minor-units-core"))]
+fn checked_add(a: Amount, b: Amount) -> Option<Amount> {
+    a.checked_add(b)
+}
+
+#[cfg(feature = "minor-units-core")]
+fn checked_add(a: Amount, b: Amount) -> Option<Amount> {
+    a.0.checked_add(b.0).map(MinorUnits)
+}
+
+// Difference of amounts, or None if it doesn't fit in the amount type
+#[cfg(not(feature = "minor-units-core"))]
+fn checked_sub(a: Amount, b: Amount) -> Option<Amount> {
+    a.checked_sub(b)
+}
+
+#[cfg(feature = "minor-units-core")]
+fn checked_sub(a: Amount, b: Amount) -> Option<Amount> {
+    a.0.checked_sub(b.0).map(MinorUnits)
+}
+
+// Sum of all amounts, or None if it doesn't fit in the amount type. Each step
+// adds the smallest remaining amount to a non-negative partial sum and the
+// largest one to a negative partial sum, so partial sums never leave the range
+// spanned by the amounts and the sum itself: only a sum which doesn't fit can
+// overflow, whatever the order of the amounts.
+fn checked_sum(mut amounts: Vec<Amount>) -> Option<Amount> {
+    amounts.sort_unstable();
+    let mut amounts = amounts.as_slice();
+    let mut sum = zero();
+    while let (Some(&smallest), Some(&largest)) = (amounts.first(), amounts.last()) {
+        let amount = if sum < zero() {
+            amounts = &amounts[..amounts.len() - 1];
+            largest
+        } else {
+            amounts = &amounts[1..];
+            smallest
+        };
+        sum = checked_add(sum, amount)?;
+    }
+    Some(sum)
+}
+
+// Sum of amounts, clamped to the range of the amount type
+#[cfg(not(feature = "minor-units-core"))]
+fn saturating_add(a: Amount, b: Amount) -> Amount {
+    match a.checked_add(b) {
+        Some(sum) => sum,
+        None if b.is_sign_negative() => Decimal::MIN,
+        None => Decimal::MAX,
+    }
+}
+
+#[cfg(feature = "minor-units-core")]
+fn saturating_add(a: Amount, b: Amount) -> Amount {
+    MinorUnits(a.0.saturating_add(b.0))
+}
+
+#[cfg(any(test, feature = "minor-units-core"))]
+const MINOR_UNITS_PER_UNIT: i128 = 10_000;
+
+// Amount expressed as an integer number of 1/10000 units. All arithmetic is exact,
+// and i128 is wide enough to hold any Decimal with at most 4 decimal places.
+#[cfg(any(test, feature = "minor-units-core"))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MinorUnits(i128);
+
+#[cfg(any(test, feature = "minor-units-core"))]
+impl MinorUnits {
+    // Fails for amounts with more than 4 decimal places, since those
+    // can't be represented exactly
+    pub fn from_decimal(value: Decimal) -> Result<Self, String> {
+        let value = value.normalize();
+        if value.scale() > DECIMAL_PLACES {
+            return Err(format!(
+                "Amount {} has more than {} decimal places",
+                value, DECIMAL_PLACES
+            ));
+        }
+        let factor = 10i128.pow(DECIMAL_PLACES - value.scale());
+        Ok(Self(value.mantissa() * factor))
+    }
+
+    #[cfg(test)]
+    fn to_decimal(self) -> Decimal {
+        Decimal::from_i128_with_scale(self.0, DECIMAL_PLACES)
+    }
+
+    #[cfg(feature = "minor-units-core")]
+    fn is_sign_negative(&self) -> bool {
+        self.0 < 0
+    }
+
+    #[cfg(feature = "minor-units-core")]
+    fn abs(&self) -> Self {
+        Self(self.0.abs())
+    }
+
+    #[cfg(feature = "minor-units-core")]
+    fn set_sign_negative(&mut self, negative: bool) {
+        self.0 = if negative {
+            -self.0.abs()
+        } else {
+            self.0.abs()
+        };
+    }
+}
+
+// Whole units, consistently with `Decimal::from`
+#[cfg(any(test, feature = "minor-units-core"))]
+impl From<i32> for MinorUnits {
+    fn from(value: i32) -> Self {
+        Self(value as i128 * MINOR_UNITS_PER_UNIT)
+    }
+}
+
+#[cfg(any(test, feature = "minor-units-core"))]
+impl FromStr for MinorUnits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = Decimal::from_str(s).map_err(|e| e.to_string())?;
+        Self::from_decimal(value)
+    }
+}
+
+// Printed the same way as a normalized Decimal, e.g. `2.6` or `-5`
+#[cfg(any(test, feature = "minor-units-core"))]
+impl fmt::Display for MinorUnits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let units = abs / MINOR_UNITS_PER_UNIT as u128;
+        let fraction = abs % MINOR_UNITS_PER_UNIT as u128;
+        if fraction == 0 {
+            return write!(f, "{}{}", sign, units);
+        }
+        let fraction = format!("{:04}", fraction);
+        write!(f, "{}{}.{}", sign, units, fraction.trim_end_matches('0'))
+    }
+}
+
+// Serialized as a string, like Decimal
+#[cfg(any(test, feature = "minor-units-core"))]
+impl Serialize for MinorUnits {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(any(test, feature = "minor-units-core"))]
+impl<'de> Deserialize<'de> for MinorUnits {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Self::from_str(&value).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(any(test, feature = "minor-units-core"))]
+impl std::ops::Add for MinorUnits {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self(self.0 + other.0)
+    }
+}
+
+#[cfg(any(test, feature = "minor-units-core"))]
+impl std::ops::Sub for MinorUnits {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self(self.0 - other.0)
+    }
+}
+
+#[cfg(feature = "minor-units-core")]
+impl std::ops::AddAssign for MinorUnits {
+    fn add_assign(&mut self, other: Self) {
+        self.0 += other.0;
+    }
+}
+
+#[cfg(feature = "minor-units-core")]
+impl std::ops::SubAssign for MinorUnits {
+    fn sub_assign(&mut self, other: Self) {
+        self.0 -= other.0;
+    }
+}
+
+// Client information consists of their available and held funds
+// and information whether and why the client is locked.
+// Total funds are not stored, since they can be trivially calculated
+// from available + held.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    available: Amount,
+    held: Amount,
+    locked: bool,
+    lock_reason: Option<LockReason>,
+}
+
+impl ClientInfo {
+    fn new(amount: Amount) -> Self {
+        Self {
+            available: amount,
+            held: 0.into(),
+            locked: false,
+            lock_reason: None,
+        }
+    }
+
+    // Constructs a client with a full state, e.g. when loading snapshots or
+    // balances, without an opening deposit
+    pub fn with_held_locked(available: Amount, held: Amount, locked: bool) -> Self {
+        Self {
+            available,
+            held,
+            locked,
+            lock_reason: None,
+        }
+    }
+
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    // Deposits and adjustments which would overflow total funds are rejected,
+    // so only a corrupt state, e.g. a hand-edited snapshot, can overflow here.
+    // The total saturates then, with a warning, so that the output can still
+    // be written.
+    pub fn total(&self) -> Amount {
+        checked_add(self.available, self.held).unwrap_or_else(|| {
+            log::warn!(
+                "Total funds overflow: available {}, held {}",
+                self.available,
+                self.held
+            );
+            saturating_add(self.available, self.held)
+        })
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn lock_reason(&self) -> Option<LockReason> {
+        self.lock_reason
+    }
+}
+
+// Balances of all clients. A hash map by default; for client ids densely
+// covering the u16 range, a flat vector indexed by client id avoids hashing
+// and is more cache-friendly, see `with_dense_client_store`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientStore {
+    Map(HashMap<ClientId, ClientInfo>),
+    Dense(DenseClients),
+}
+
+// Clients in a flat vector indexed by client id. Fields are private, so that
+// `len` always counts the occupied slots; it's recomputed when deserialized.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<Option<ClientInfo>>", into = "Vec<Option<ClientInfo>>")]
+pub struct DenseClients {
+    slots: Vec<Option<ClientInfo>>,
+    len: usize,
+}
+
+impl From<Vec<Option<ClientInfo>>> for DenseClients {
+    fn from(slots: Vec<Option<ClientInfo>>) -> Self {
+        let len = slots.iter().filter(|info| info.is_some()).count();
+        DenseClients { slots, len }
+    }
+}
+
+impl From<DenseClients> for Vec<Option<ClientInfo>> {
+    fn from(clients: DenseClients) -> Self {
+        clients.slots
+    }
+}
+
+impl DenseClients {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, client: &ClientId) -> Option<&ClientInfo> {
+        self.slots.get(*client as usize)?.as_ref()
+    }
+
+    fn get_mut(&mut self, client: &ClientId) -> Option<&mut ClientInfo> {
+        self.slots.get_mut(*client as usize)?.as_mut()
+    }
+
+    fn insert(&mut self, client: ClientId, info: ClientInfo) -> Option<ClientInfo> {
+        let index = client as usize;
+        if self.slots.len() <= index {
+            self.slots.resize(index + 1, None);
+        }
+        let old = self.slots[index].replace(info);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // In client id order, skipping empty slots
+    pub fn iter(&self) -> impl Iterator<Item = (ClientId, &ClientInfo)> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(id, info)| Some((id as ClientId, info.as_ref()?)))
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (ClientId, &mut ClientInfo)> + '_ {
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(id, info)| Some((id as ClientId, info.as_mut()?)))
+    }
+}
+
+impl Default for ClientStore {
+    fn default() -> Self {
+        ClientStore::Map(HashMap::default())
+    }
+}
+
+// Stores with the same clients are equal, regardless of how they're backed
+impl PartialEq for ClientStore {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(id, info)| other.get(&id) == Some(info))
+    }
+}
+
+impl std::ops::Index<&ClientId> for ClientStore {
+    type Output = ClientInfo;
+
+    fn index(&self, client: &ClientId) -> &ClientInfo {
+        self.get(client).expect("no such client")
+    }
+}
+
+impl ClientStore {
+    pub fn get(&self, client: &ClientId) -> Option<&ClientInfo> {
+        match self {
+            ClientStore::Map(map) => map.get(client),
+            ClientStore::Dense(dense) => dense.get(client),
+        }
+    }
+
+    fn get_mut(&mut self, client: &ClientId) -> Option<&mut ClientInfo> {
+        match self {
+            ClientStore::Map(map) => map.get_mut(client),
+            ClientStore::Dense(dense) => dense.get_mut(client),
+        }
+    }
+
+    pub fn contains_key(&self, client: &ClientId) -> bool {
+        self.get(client).is_some()
+    }
+
+    fn insert(&mut self, client: ClientId, info: ClientInfo) -> Option<ClientInfo> {
+        match self {
+            ClientStore::Map(map) => map.insert(client, info),
+            ClientStore::Dense(dense) => dense.insert(client, info),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ClientStore::Map(map) => map.len(),
+            ClientStore::Dense(dense) => dense.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Dense stores iterate in client id order
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (ClientId, &ClientInfo)> + '_> {
+        match self {
+            ClientStore::Map(map) => Box::new(map.iter().map(|(id, info)| (*id, info))),
+            ClientStore::Dense(dense) => Box::new(dense.iter()),
+        }
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = (ClientId, &mut ClientInfo)> + '_> {
+        match self {
+            ClientStore::Map(map) => Box::new(map.iter_mut().map(|(id, info)| (*id, info))),
+            ClientStore::Dense(dense) => Box::new(dense.iter_mut()),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.iter().map(|(id, _)| id)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &ClientInfo> + '_ {
+        self.iter().map(|(_, info)| info)
+    }
+}
+
+// Why a client got locked, for investigating frozen accounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockReason {
+    Chargeback { tx: TxId },
+    // Frozen manually with `freeze`
+    Admin,
+    // Too many attempts to dispute an open dispute again, the last one of
+    // transaction `tx`, see `with_redispute_lock`
+    RepeatedDisputes { tx: TxId },
+}
+
+// Printed as `chargeback:<tx>`, `admin` or `repeated_disputes:<tx>`
+impl fmt::Display for LockReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockReason::Chargeback { tx } => write!(f, "chargeback:{}", tx),
+            LockReason::Admin => write!(f, "admin"),
+            LockReason::RepeatedDisputes { tx } => write!(f, "repeated_disputes:{}", tx),
+        }
+    }
+}
+
+impl FromStr for LockReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("chargeback", tx)) => tx
+                .parse()
+                .map(|tx| LockReason::Chargeback { tx })
+                .map_err(|e| format!("Invalid lock reason {}: {}", s, e)),
+            Some(("repeated_disputes", tx)) => tx
+                .parse()
+                .map(|tx| LockReason::RepeatedDisputes { tx })
+                .map_err(|e| format!("Invalid lock reason {}: {}", s, e)),
+            None if s == "admin" => Ok(LockReason::Admin),
+            _ => Err(format!("Invalid lock reason {}", s)),
+        }
+    }
+}
+
+// There's no specific type associated with deposit or withdrawal,
+// because it only introduces complications in the type system and no gains:
+// the transaction simply uses positive/negative amounts
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Transaction {
+    client: ClientId,
+    amount: Amount,
+}
+
+// Applied operator correction, kept for auditing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Adjustment {
+    pub client: ClientId,
+    // Signed change of available funds
+    pub amount: Amount,
+    pub note: String,
+}
+
+// Type column of an input row; unknown types are rejected when the row is parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Void,
+    Refund,
+    Adjustment,
+    Unlock,
+    Amend,
+    // Moves funds to the client in the `target` column
+    Transfer,
+    // Deposit or withdrawal depending on the sign, see `with_signed_amounts`
+    Transaction,
+}
+
+impl OperationType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OperationType::Deposit => "deposit",
+            OperationType::Withdrawal => "withdrawal",
+            OperationType::Dispute => "dispute",
+            OperationType::Resolve => "resolve",
+            OperationType::Chargeback => "chargeback",
+            OperationType::Void => "void",
+            OperationType::Refund => "refund",
+            OperationType::Adjustment => "adjustment",
+            OperationType::Unlock => "unlock",
+            OperationType::Amend => "amend",
+            OperationType::Transfer => "transfer",
+            OperationType::Transaction => "transaction",
+        }
+    }
+}
+
+// A single operation on the engine, carrying exactly the fields it needs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Deposit {
+        tx: TxId,
+        client: ClientId,
+        amount: Amount,
+    },
+    Withdrawal {
+        tx: TxId,
+        client: ClientId,
+        amount: Amount,
+    },
+    Dispute {
+        tx: TxId,
+        client: ClientId,
+    },
+    Resolve {
+        tx: TxId,
+        client: ClientId,
+    },
+    Chargeback {
+        tx: TxId,
+        client: ClientId,
+    },
+    // Cancels a deposit which hasn't settled yet
+    Void {
+        tx: TxId,
+        client: ClientId,
+    },
+    // Returns a deposit to the party which made it, e.g. a merchant-initiated
+    // refund; applied like a void, but recorded as a refund
+    Refund {
+        tx: TxId,
+        client: ClientId,
+    },
+    // Operator correction of available funds by a signed amount
+    Adjustment {
+        tx: TxId,
+        client: ClientId,
+        amount: Amount,
+    },
+    // Lifts the lock of a client, e.g. once an operator cleared a fraud flag;
+    // the id only identifies the row and isn't stored
+    Unlock {
+        tx: TxId,
+        client: ClientId,
+    },
+    // Corrects the amount of a stored deposit, e.g. one recorded again in
+    // a later file, changing available funds by the difference
+    Amend {
+        tx: TxId,
+        client: ClientId,
+        amount: Amount,
+    },
+    // Moves available funds from `client` to `target` as a single operation.
+    // It's stored as a transaction of the target, which can dispute it like
+    // a deposit.
+    Transfer {
+        tx: TxId,
+        client: ClientId,
+        target: ClientId,
+        amount: Amount,
+    },
+}
+
+impl Operation {
+    // Returns None for signed-amount transactions, see `from_signed_amount`,
+    // and for transfers, which need a target
+    fn from_row(op: OperationType, tx: TxId, client: ClientId, amount: Amount) -> Option<Self> {
+        match op {
+            OperationType::Deposit => Some(Operation::Deposit { tx, client, amount }),
+            OperationType::Withdrawal => Some(Operation::Withdrawal { tx, client, amount }),
+            OperationType::Dispute => Some(Operation::Dispute { tx, client }),
+            OperationType::Resolve => Some(Operation::Resolve { tx, client }),
+            OperationType::Chargeback => Some(Operation::Chargeback { tx, client }),
+            OperationType::Void => Some(Operation::Void { tx, client }),
+            OperationType::Refund => Some(Operation::Refund { tx, client }),
+            OperationType::Adjustment => Some(Operation::Adjustment { tx, client, amount }),
+            OperationType::Unlock => Some(Operation::Unlock { tx, client }),
+            OperationType::Amend => Some(Operation::Amend { tx, client, amount }),
+            OperationType::Transfer | OperationType::Transaction => None,
+        }
+    }
+
+    // Interprets a generic signed-amount transaction: negative amounts are
+    // withdrawals, others are deposits
+    fn from_signed_amount(tx: TxId, client: ClientId, amount: Amount) -> Self {
+        if amount.is_sign_negative() {
+            Operation::Withdrawal {
+                tx,
+                client,
+                amount: amount.abs(),
+            }
+        } else {
+            Operation::Deposit { tx, client, amount }
+        }
+    }
+
+    pub fn tx(&self) -> TxId {
+        match *self {
+            Operation::Deposit { tx, .. }
+            | Operation::Withdrawal { tx, .. }
+            | Operation::Dispute { tx, .. }
+            | Operation::Resolve { tx, .. }
+            | Operation::Chargeback { tx, .. }
+            | Operation::Void { tx, .. }
+            | Operation::Refund { tx, .. }
+            | Operation::Adjustment { tx, .. }
+            | Operation::Unlock { tx, .. }
+            | Operation::Amend { tx, .. }
+            | Operation::Transfer { tx, .. } => tx,
+        }
+    }
+
+    pub fn client(&self) -> ClientId {
+        match *self {
+            Operation::Deposit { client, .. }
+            | Operation::Withdrawal { client, .. }
+            | Operation::Dispute { client, .. }
+            | Operation::Resolve { client, .. }
+            | Operation::Chargeback { client, .. }
+            | Operation::Void { client, .. }
+            | Operation::Refund { client, .. }
+            | Operation::Adjustment { client, .. }
+            | Operation::Unlock { client, .. }
+            | Operation::Amend { client, .. }
+            | Operation::Transfer { client, .. } => client,
+        }
+    }
+
+    // Operation type as in the input
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::Deposit { .. } => "deposit",
+            Operation::Withdrawal { .. } => "withdrawal",
+            Operation::Dispute { .. } => "dispute",
+            Operation::Resolve { .. } => "resolve",
+            Operation::Chargeback { .. } => "chargeback",
+            Operation::Void { .. } => "void",
+            Operation::Refund { .. } => "refund",
+            Operation::Adjustment { .. } => "adjustment",
+            Operation::Unlock { .. } => "unlock",
+            Operation::Amend { .. } => "amend",
+            Operation::Transfer { .. } => "transfer",
+        }
+    }
+}
+
+// Balances of the traced client right after an operation, see `with_client_trace`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TracePoint {
+    pub op: String,
+    pub tx: TxId,
+    pub available: Amount,
+    pub held: Amount,
+}
+
+// Applied operations written out as they're applied, see `with_operation_log`
+struct OperationLog {
+    writer: csv::Writer<Box<dyn Write + Send>>,
+    // First write error, reported by `finish_operation_log`
+    error: Option<io::Error>,
+}
+
+impl OperationLog {
+    fn append(&mut self, op: &Operation, note: Option<&str>) {
+        if self.error.is_some() {
+            return;
+        }
+        let amount = match op {
+            Operation::Deposit { amount, .. }
+            | Operation::Withdrawal { amount, .. }
+            | Operation::Adjustment { amount, .. }
+            | Operation::Amend { amount, .. }
+            | Operation::Transfer { amount, .. } => amount.to_string(),
+            _ => String::new(),
+        };
+        let target = match op {
+            Operation::Transfer { target, .. } => target.to_string(),
+            _ => String::new(),
+        };
+        let record = [
+            op.name(),
+            &op.client().to_string(),
+            &op.tx().to_string(),
+            &amount,
+            note.unwrap_or(""),
+            &target,
+        ];
+        if let Err(e) = self.writer.write_record(record) {
+            log::error!("Failed to write the operation log: {}", e);
+            self.error = Some(e.into());
+        }
+    }
+}
+
+impl fmt::Debug for OperationLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OperationLog").finish_non_exhaustive()
+    }
+}
+
+// The log is output rather than state, so it doesn't affect comparisons of engines
+impl PartialEq for OperationLog {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+// Serializable as a whole, e.g. for persisting state between runs
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TransactionEngine {
+    // Performance note: if the number of clients is expected to be close to u16::MAX,
+    // it's better to use a flat array of clients, which is more cache-friendly
+    // and allows much faster lookups, see `with_dense_client_store`
+    clients: ClientStore,
+    transactions: HashMap<TxId, Transaction>,
+    disputed: HashSet<TxId>,
+    charged_back: HashSet<TxId>,
+    voided: HashSet<TxId>,
+    // Voided deposits which were refunded
+    refunded: HashSet<TxId>,
+    header_aliases: HashMap<String, String>,
+    disputes_disabled: bool,
+    streaming: bool,
+    adjustments_forbidden: bool,
+    adjustments: BTreeMap<TxId, Adjustment>,
+    // Source client of each stored transfer; the target is the transaction's client
+    transfer_sources: HashMap<TxId, ClientId>,
+    signed_amounts: bool,
+    reverse_order: bool,
+    recycle_tx_ids: bool,
+    zero_tx_id_rejected: bool,
+    client_filter: Option<HashSet<ClientId>>,
+    precision_warnings: bool,
+    resolve_cooldown: Option<u64>,
+    resolved_at: HashMap<TxId, u64>,
+    redispute_limit: Option<u32>,
+    traced_client: Option<ClientId>,
+    trace: Vec<TracePoint>,
+    #[serde(skip)]
+    operation_log: Option<OperationLog>,
+    // Attempts of each client to dispute its already disputed transactions
+    redisputes: HashMap<ClientId, u32>,
+    // Set when the operation being applied had to round a balance
+    #[serde(skip)]
+    lost_precision: bool,
+    // Part of each deposit not drawn down by withdrawals yet, per client
+    remaining: HashMap<ClientId, BTreeMap<TxId, Amount>>,
+    // Amount moved into `held` by each dispute, kept after a chargeback
+    dispute_amounts: HashMap<TxId, Amount>,
+    // Parts of deposits drawn by each withdrawal, restored when it's disputed
+    draws: HashMap<TxId, Vec<(TxId, Amount)>>,
+    #[cfg(feature = "row-hash")]
+    row_hash_key: Option<Vec<u8>>,
+    // RoundingStrategy isn't serializable, so it has to be configured again
+    // after deserializing
+    #[serde(skip)]
+    input_rounding: Option<RoundingStrategy>,
+    row_limit: Option<usize>,
+    sampling: Option<Sampling>,
+    anonymize_seed: Option<u64>,
+    header_policy: HeaderPolicy,
+    dispute_source: DisputeSource,
+    dispute_policy: DisputePolicy,
+    dispute_scope: DisputeScope,
+    // Transactions applied from the current input, tracked with `DisputeScope::PerFile`
+    #[serde(skip)]
+    input_txs: HashSet<TxId>,
+    held_invariant: bool,
+    strict_dispute_amounts: bool,
+    // Number of CSV records read, malformed ones included
+    rows_read: u64,
+    fixed_point_output: bool,
+    lock_reason_column: bool,
+    volume_buckets: Option<BucketSize>,
+    headerless: bool,
+    input_buffer_size: Option<usize>,
+    track_disputes: bool,
+    dispute_log: Vec<DisputeRecord>,
+    // Index of the open dispute of each transaction in `dispute_log`
+    open_disputes: HashMap<TxId, usize>,
+    volumes: BTreeMap<u64, Volume>,
+    output_fields: Option<Vec<OutputField>>,
+    external_ids: HashMap<String, ClientId>,
+    warnings: Vec<Warning>,
+    warning_counts: HashMap<String, usize>,
+    op_counts: HashMap<ClientId, u64>,
+    next_warning_seq: u64,
+    clamped_dust: Vec<ClampedDust>,
+    // Part of resumed clients' total funds which the journal doesn't account
+    // for, e.g. dust clamped in a previous period
+    seeded: HashMap<ClientId, Amount>,
+    seq: u64,
+    track_changes: bool,
+    client_seqs: HashMap<ClientId, u64>,
+    tx_seqs: HashMap<TxId, u64>,
+}
+
+// State of the engine as of given sequence number: either all clients
+// and transactions, or only the ones changed since a previous snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub seq: u64,
+    // Both sorted by id, so that snapshots of the same state are equal
+    clients: Vec<(ClientId, ClientInfo)>,
+    transactions: Vec<(TxId, TransactionState)>,
+    #[serde(default)]
+    adjustments: Vec<(TxId, Adjustment)>,
+}
+
+impl Snapshot {
+    // Transactions come sorted already, see `sorted_transactions`
+    fn sorted(mut self) -> Self {
+        self.clients.sort_unstable_by_key(|(id, _)| *id);
+        self
+    }
+
+    pub fn write_json<W: Write>(&self, writer: W) -> io::Result<()> {
+        serde_json::to_writer(writer, self).map_err(io::Error::from)
+    }
+
+    pub fn read_json<R: io::Read>(reader: R) -> io::Result<Self> {
+        serde_json::from_reader(reader).map_err(io::Error::from)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TransactionState {
+    transaction: Transaction,
+    disputed: bool,
+    charged_back: bool,
+    voided: bool,
+    #[serde(default)]
+    refunded: bool,
+    // Source client of a transfer
+    #[serde(default)]
+    source: Option<ClientId>,
+    remaining: Option<Amount>,
+    dispute_amount: Option<Amount>,
+    draws: Vec<(TxId, Amount)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Balance {
+    Available,
+    Held,
+}
+
+// Balance zeroed out by `clamp_dust`, along with the amount it used to hold
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClampedDust {
+    pub client: ClientId,
+    pub balance: Balance,
+    pub amount: Amount,
+}
+
+// Error which stops processing altogether, or the reason for rejecting
+// a single operation, see `apply_operation`
+#[derive(Debug)]
+pub enum EngineError {
+    Io(io::Error),
+    Csv(csv::Error),
+    TransactionExists(TxId),
+    TransactionNotFound(TxId),
+    InsufficientFunds {
+        client: ClientId,
+        requested: Amount,
+        available: Amount,
+    },
+    ClientLocked(ClientId),
+    ClientMismatch {
+        tx: TxId,
+        client: ClientId,
+    },
+    NegativeAmount(Amount),
+    AlreadyDisputed(TxId),
+    // Any other reason for rejecting an operation
+    Rejected(WarningKind),
+}
+
+impl EngineError {
+    // Warning recorded for the rejection, or for a failure while reading input
+    fn warning_kind(&self) -> WarningKind {
+        match self {
+            EngineError::Io(e) => WarningKind::InvalidRow(e.to_string()),
+            EngineError::Csv(e) => WarningKind::InvalidRow(e.to_string()),
+            EngineError::TransactionExists(tx) => WarningKind::TransactionExists(*tx),
+            EngineError::TransactionNotFound(tx) => WarningKind::TransactionNotFound(*tx),
+            EngineError::InsufficientFunds {
+                client,
+                requested,
+                available,
+            } => WarningKind::InsufficientFunds {
+                client: *client,
+                requested: *requested,
+                available: *available,
+            },
+            EngineError::ClientLocked(client) => WarningKind::ClientLocked(*client),
+            EngineError::ClientMismatch { tx, client } => WarningKind::ClientMismatch {
+                tx: *tx,
+                client: *client,
+            },
+            EngineError::NegativeAmount(amount) => WarningKind::NegativeAmount(*amount),
+            EngineError::AlreadyDisputed(tx) => WarningKind::AlreadyDisputed(*tx),
+            EngineError::Rejected(kind) => kind.clone(),
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::Io(e) => write!(f, "{}", e),
+            EngineError::Csv(e) => write!(f, "{}", e),
+            rejection => write!(f, "{}", rejection.warning_kind()),
+        }
+    }
+}
+
+// I/O and CSV errors only compare equal by their message
+impl PartialEq for EngineError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (EngineError::Io(e), EngineError::Io(other)) => e.to_string() == other.to_string(),
+            (EngineError::Csv(e), EngineError::Csv(other)) => e.to_string() == other.to_string(),
+            (EngineError::Io(_) | EngineError::Csv(_), _)
+            | (_, EngineError::Io(_) | EngineError::Csv(_)) => false,
+            (rejection, other) => rejection.warning_kind() == other.warning_kind(),
+        }
+    }
+}
+
+impl From<WarningKind> for EngineError {
+    fn from(kind: WarningKind) -> Self {
+        match kind {
+            WarningKind::TransactionExists(tx) => EngineError::TransactionExists(tx),
+            WarningKind::TransactionNotFound(tx) => EngineError::TransactionNotFound(tx),
+            WarningKind::InsufficientFunds {
+                client,
+                requested,
+                available,
+            } => EngineError::InsufficientFunds {
+                client,
+                requested,
+                available,
+            },
+            WarningKind::ClientLocked(client) => EngineError::ClientLocked(client),
+            WarningKind::ClientMismatch { tx, client } => {
+                EngineError::ClientMismatch { tx, client }
+            }
+            WarningKind::NegativeAmount(amount) => EngineError::NegativeAmount(amount),
+            WarningKind::AlreadyDisputed(tx) => EngineError::AlreadyDisputed(tx),
+            kind => EngineError::Rejected(kind),
+        }
+    }
+}
+
+impl From<EngineError> for WarningKind {
+    fn from(e: EngineError) -> Self {
+        e.warning_kind()
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl From<io::Error> for EngineError {
+    fn from(e: io::Error) -> Self {
+        EngineError::Io(e)
+    }
+}
+
+impl From<csv::Error> for EngineError {
+    fn from(e: csv::Error) -> Self {
+        if !e.is_io_error() {
+            return EngineError::Csv(e);
+        }
+        match e.into_kind() {
+            csv::ErrorKind::Io(e) => EngineError::Io(e),
+            _ => unreachable!("checked by is_io_error"),
+        }
+    }
+}
+
+// Effect of a successfully applied operation on the client's balances
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpOutcome {
+    pub client: ClientId,
+    pub available_delta: Amount,
+    pub held_delta: Amount,
+    pub locked: bool,
+    // Balances had to be rounded, since they no longer fit in the amount type
+    pub lost_precision: bool,
+}
+
+// Diagnostic emitted while processing; every rejected operation or row
+// produces one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Warning {
+    // Monotonic sequence number, unique within the engine even across drains
+    pub seq: u64,
+    // Line in the input file, if the warning comes from a CSV row
+    pub line: Option<u64>,
+    pub kind: WarningKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WarningKind {
+    InvalidRow(String),
+    InvalidAmount(String),
+    UnknownOperation(String),
+    MissingClient(TxId),
+    ExternalIdsExhausted(String),
+    TransactionExists(TxId),
+    TransactionNotFound(TxId),
+    NegativeAmount(Amount),
+    ZeroTxId,
+    MissingTarget(TxId),
+    SelfTransfer(TxId),
+    ClientLocked(ClientId),
+    ClientNotLocked(ClientId),
+    ClientNotFound(ClientId),
+    ClientMismatch {
+        tx: TxId,
+        client: ClientId,
+    },
+    InsufficientFunds {
+        client: ClientId,
+        requested: Amount,
+        available: Amount,
+    },
+    AlreadyDisputed(TxId),
+    ResolveNotDisputed(TxId),
+    ChargebackNotDisputed(TxId),
+    TransactionChargedBack(TxId),
+    TransactionVoided(TxId),
+    TransactionRefunded(TxId),
+    NotADeposit(TxId),
+    DisputesDisabled(TxId),
+    FullyWithdrawn(TxId),
+    ExcessivePrecision {
+        tx: TxId,
+        scale: u32,
+    },
+    TooManyDecimalPlaces {
+        tx: TxId,
+        scale: u32,
+    },
+    ResolveCooldown {
+        tx: TxId,
+        remaining: u64,
+    },
+    PrecisionLoss {
+        tx: TxId,
+        client: ClientId,
+    },
+    DisputeExceedsAvailable {
+        tx: TxId,
+        amount: Amount,
+        available: Amount,
+    },
+    ExceedsHeld {
+        tx: TxId,
+        amount: Amount,
+        held: Amount,
+    },
+    HeldOutOfBounds {
+        tx: TxId,
+        held: Amount,
+        total: Amount,
+    },
+    AdjustmentsForbidden(TxId),
+    WithdrawalDisputeDisallowed(TxId),
+    DisputeOutsideFile(TxId),
+    DisputeAmountUnknown(TxId),
+    DisputeAmountMismatch {
+        tx: TxId,
+        disputed: Amount,
+        moved: Amount,
+    },
+    Overflow {
+        tx: TxId,
+        client: ClientId,
+    },
+}
+
+impl WarningKind {
+    // Short, stable name of the warning kind, e.g. for aggregating counts
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningKind::InvalidRow { .. } => "invalid_row",
+            WarningKind::InvalidAmount { .. } => "invalid_amount",
+            WarningKind::UnknownOperation { .. } => "unknown_operation",
+            WarningKind::MissingClient { .. } => "missing_client",
+            WarningKind::ExternalIdsExhausted { .. } => "external_ids_exhausted",
+            WarningKind::TransactionExists { .. } => "transaction_exists",
+            WarningKind::TransactionNotFound { .. } => "transaction_not_found",
+            WarningKind::NegativeAmount { .. } => "negative_amount",
+            WarningKind::ZeroTxId => "zero_tx_id",
+            WarningKind::MissingTarget { .. } => "missing_target",
+            WarningKind::SelfTransfer { .. } => "self_transfer",
+            WarningKind::ClientLocked { .. } => "client_locked",
+            WarningKind::ClientNotLocked { .. } => "client_not_locked",
+            WarningKind::ClientNotFound { .. } => "client_not_found",
+            WarningKind::ClientMismatch { .. } => "client_mismatch",
+            WarningKind::InsufficientFunds { .. } => "insufficient_funds",
+            WarningKind::AlreadyDisputed { .. } => "already_disputed",
+            WarningKind::ResolveNotDisputed { .. } => "resolve_not_disputed",
+            WarningKind::ChargebackNotDisputed { .. } => "chargeback_not_disputed",
+            WarningKind::TransactionChargedBack { .. } => "transaction_charged_back",
+            WarningKind::TransactionVoided { .. } => "transaction_voided",
+            WarningKind::TransactionRefunded { .. } => "transaction_refunded",
+            WarningKind::NotADeposit { .. } => "not_a_deposit",
+            WarningKind::DisputesDisabled { .. } => "disputes_disabled",
+            WarningKind::FullyWithdrawn { .. } => "fully_withdrawn",
+            WarningKind::ExcessivePrecision { .. } => "excessive_precision",
+            WarningKind::TooManyDecimalPlaces { .. } => "too_many_decimal_places",
+            WarningKind::ResolveCooldown { .. } => "resolve_cooldown",
+            WarningKind::PrecisionLoss { .. } => "precision_loss",
+            WarningKind::DisputeExceedsAvailable { .. } => "dispute_exceeds_available",
+            WarningKind::ExceedsHeld { .. } => "exceeds_held",
+            WarningKind::HeldOutOfBounds { .. } => "held_out_of_bounds",
+            WarningKind::AdjustmentsForbidden { .. } => "adjustments_forbidden",
+            WarningKind::WithdrawalDisputeDisallowed { .. } => "withdrawal_dispute_disallowed",
+            WarningKind::DisputeOutsideFile { .. } => "dispute_outside_file",
+            WarningKind::DisputeAmountUnknown { .. } => "dispute_amount_unknown",
+            WarningKind::DisputeAmountMismatch { .. } => "dispute_amount_mismatch",
+            WarningKind::Overflow { .. } => "overflow",
+        }
+    }
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WarningKind::InvalidRow(e) => write!(f, "Invalid row: {}", e),
+            WarningKind::InvalidAmount(e) => write!(f, "Invalid amount: {}", e),
+            WarningKind::UnknownOperation(op) => write!(f, "Unknown transaction type {}", op),
+            WarningKind::MissingClient(tx) => {
+                write!(f, "Missing client id for transaction {}", tx)
+            }
+            WarningKind::ExternalIdsExhausted(external_id) => {
+                write!(f, "No client id left for external id {}", external_id)
+            }
+            WarningKind::TransactionExists(tx) => write!(f, "Transaction {} already exists", tx),
+            WarningKind::TransactionNotFound(tx) => {
+                write!(f, "Transaction {} does not exist", tx)
+            }
+            WarningKind::NegativeAmount(amount) => {
+                write!(f, "Invalid negative amount: {}", amount)
+            }
+            WarningKind::ZeroTxId => write!(f, "Invalid transaction id 0"),
+            WarningKind::MissingTarget(tx) => write!(f, "Transfer {} has no target client", tx),
+            WarningKind::SelfTransfer(tx) => {
+                write!(f, "Transfer {} has the same source and target client", tx)
+            }
+            WarningKind::ClientLocked(client) => write!(f, "Client {} locked", client),
+            WarningKind::ClientNotLocked(client) => {
+                write!(f, "Client {} not locked, nothing to unlock", client)
+            }
+            WarningKind::ClientNotFound(client) => write!(f, "No such client: {}", client),
+            WarningKind::ClientMismatch { tx, client } => write!(
+                f,
+                "Transaction {} doesn't match the client id {}, skipping",
+                tx, client
+            ),
+            WarningKind::InsufficientFunds {
+                requested,
+                available,
+                ..
+            } => write!(
+                f,
+                "Not enough funds {} for withdrawing {}",
+                available, requested
+            ),
+            WarningKind::AlreadyDisputed(tx) => write!(f, "Transaction {} already disputed", tx),
+            WarningKind::ResolveNotDisputed(tx) => {
+                write!(f, "Can't resolve transaction {}, it's not disputed", tx)
+            }
+            WarningKind::ChargebackNotDisputed(tx) => {
+                write!(f, "Can't charge back transaction {}, it's not disputed", tx)
+            }
+            WarningKind::TransactionChargedBack(tx) => {
+                write!(f, "Transaction {} already charged back", tx)
+            }
+            WarningKind::TransactionVoided(tx) => write!(f, "Transaction {} voided", tx),
+            WarningKind::TransactionRefunded(tx) => write!(f, "Transaction {} refunded", tx),
+            WarningKind::NotADeposit(tx) => write!(f, "Transaction {} is not a deposit", tx),
+            WarningKind::ResolveCooldown { tx, remaining } => write!(
+                f,
+                "Transaction {} was resolved recently, can be disputed again after {} more operations",
+                tx, remaining
+            ),
+            WarningKind::ExcessivePrecision { tx, scale } => write!(
+                f,
+                "Transaction {} has an amount with {} decimal places, rounded to {}",
+                tx, scale, DECIMAL_PLACES
+            ),
+            WarningKind::TooManyDecimalPlaces { tx, scale } => write!(
+                f,
+                "Transaction {} has an amount with {} decimal places, more than {}",
+                tx, scale, DECIMAL_PLACES
+            ),
+            WarningKind::PrecisionLoss { tx, client } => write!(
+                f,
+                "Balances of client {} had to be rounded when applying transaction {}",
+                client, tx
+            ),
+            WarningKind::FullyWithdrawn(tx) => {
+                write!(f, "Transaction {} was fully withdrawn, nothing to dispute", tx)
+            }
+            WarningKind::DisputesDisabled(tx) => {
+                write!(
+                    f,
+                    "Can't refer to transaction {}, disputes are disabled",
+                    tx
+                )
+            }
+            WarningKind::DisputeExceedsAvailable {
+                amount, available, ..
+            } => write!(
+                f,
+                "Disputed amount {} larger than available funds: {}, skipping",
+                amount, available
+            ),
+            WarningKind::Overflow { tx, client } => write!(
+                f,
+                "Transaction {} would overflow balances of client {}, skipping",
+                tx, client
+            ),
+            WarningKind::DisputeAmountUnknown(tx) => {
+                write!(f, "No disputed amount recorded for transaction {}", tx)
+            }
+            WarningKind::DisputeAmountMismatch {
+                tx,
+                disputed,
+                moved,
+            } => write!(
+                f,
+                "Transaction {} would move {} instead of the disputed {}, skipping",
+                tx, moved, disputed
+            ),
+            WarningKind::WithdrawalDisputeDisallowed(tx) => {
+                write!(f, "Transaction {} is a withdrawal, which can't be disputed", tx)
+            }
+            WarningKind::DisputeOutsideFile(tx) => write!(
+                f,
+                "Transaction {} comes from another file, which can't be disputed here",
+                tx
+            ),
+            WarningKind::AdjustmentsForbidden(tx) => {
+                write!(f, "Adjustment {} rejected, adjustments are forbidden", tx)
+            }
+            WarningKind::HeldOutOfBounds { tx, held, total } => write!(
+                f,
+                "Dispute of transaction {} would leave held funds {} outside of 0..={}, skipping",
+                tx, held, total
+            ),
+            WarningKind::ExceedsHeld { tx, amount, held } => write!(
+                f,
+                "Amount {} of transaction {} larger than held funds: {}, skipping",
+                amount, tx, held
+            ),
+        }
+    }
+}
+
+// Carried by `EngineError::Rejected`
+impl std::error::Error for WarningKind {}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "Line {}: {}", line, self.kind),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+// Controls when the header row is written to the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HeaderPolicy {
+    #[default]
+    Always,
+    // Some strict parsers expect no output at all when there's no data
+    WhenNonEmpty,
+}
+
+// Column of the balances output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputField {
+    Client,
+    ExternalId,
+    Available,
+    Held,
+    Total,
+    Locked,
+    LockReason,
+    #[cfg(feature = "row-hash")]
+    Hash,
+}
+
+impl OutputField {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputField::Client => "client",
+            OutputField::ExternalId => "external_id",
+            OutputField::Available => "available",
+            OutputField::Held => "held",
+            OutputField::Total => "total",
+            OutputField::Locked => "locked",
+            OutputField::LockReason => "lock_reason",
+            #[cfg(feature = "row-hash")]
+            OutputField::Hash => "hash",
+        }
+    }
+}
+
+impl FromStr for OutputField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(OutputField::Client),
+            "external_id" => Ok(OutputField::ExternalId),
+            "available" => Ok(OutputField::Available),
+            "held" => Ok(OutputField::Held),
+            "total" => Ok(OutputField::Total),
+            "locked" => Ok(OutputField::Locked),
+            "lock_reason" => Ok(OutputField::LockReason),
+            #[cfg(feature = "row-hash")]
+            "hash" => Ok(OutputField::Hash),
+            _ => Err(format!("Unknown output field {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BucketSize {
+    Hourly,
+    Daily,
+}
+
+impl BucketSize {
+    fn seconds(self) -> u64 {
+        match self {
+            BucketSize::Hourly => 60 * 60,
+            BucketSize::Daily => 24 * 60 * 60,
+        }
+    }
+}
+
+// Total deposited and withdrawn amounts within a time bucket
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Volume {
+    deposits: Amount,
+    withdrawals: Amount,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputeOutcome {
+    Open,
+    Resolved,
+    ChargedBack,
+}
+
+impl DisputeOutcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisputeOutcome::Open => "open",
+            DisputeOutcome::Resolved => "resolved",
+            DisputeOutcome::ChargedBack => "chargedback",
+        }
+    }
+}
+
+// Applied dispute, with the amount it held
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct DisputeRecord {
+    tx: TxId,
+    client: ClientId,
+    amount: Amount,
+    outcome: DisputeOutcome,
+}
+
+// Funds a dispute has to fit in to be applied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputeSource {
+    #[default]
+    AvailableOnly,
+    // Funds already held by other disputes count too, so available funds
+    // may go negative
+    TotalFunds,
+}
+
+// Fraction of rows to process and the seed choosing them, see `with_sample_rate`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Sampling {
+    rate: f64,
+    seed: u64,
+}
+
+impl Sampling {
+    // Picks the row at given index with probability `rate`, the same rows
+    // each time for the same seed
+    fn picks(&self, index: u64) -> bool {
+        let x = mix(self.seed, index);
+        // Top 53 bits as a uniform fraction in [0, 1)
+        ((x >> 11) as f64 / (1u64 << 53) as f64) < self.rate
+    }
+}
+
+// SplitMix64 finalizer, mixing the value with the seed well enough for
+// neighbouring values to give independent results
+fn mix(seed: u64, value: u64) -> u64 {
+    let mut x = seed ^ value.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// Pseudonymous id of the client in anonymized output, see `with_anonymized_output`.
+// A Feistel network over the two bytes of the id is a permutation of all ids,
+// so distinct clients never share a pseudonym, while the seed decides which
+// permutation it is.
+fn pseudonym(seed: u64, client: ClientId) -> ClientId {
+    let (mut left, mut right) = ((client >> 8) as u8, client as u8);
+    for round in 0..4 {
+        let key = mix(seed, round << 8 | u64::from(right)) as u8;
+        let next = left ^ key;
+        left = right;
+        right = next;
+    }
+    ClientId::from(left) << 8 | ClientId::from(right)
+}
+
+// Which transactions can be disputed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputePolicy {
+    // Withdrawals can be disputed too, giving the withdrawn funds back to
+    // `available` while `held` goes negative; available funds still may not
+    // go below zero
+    #[default]
+    AllowWithdrawals,
+    // Disputes of withdrawals are rejected
+    DepositsOnly,
+}
+
+// Which earlier transactions a dispute can refer to when several inputs are
+// applied to the same engine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputeScope {
+    // Any transaction applied so far
+    #[default]
+    Global,
+    // Only transactions from the same input, e.g. file, as the dispute, for
+    // pipelines treating each file as self-contained
+    PerFile,
+}
+
+// Transaction engine capable of serving deposits, withdrawals, disputes,
+// resolves and chargebacks
+impl TransactionEngine {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Rounds input amounts to 4 decimal places with given strategy. Rounding
+    // is applied before an amount is recorded or applied to balances, so that
+    // transactions and balances always agree on the rounded value and
+    // a later dispute can't be spuriously rejected due to rounding.
+    pub fn with_input_rounding(mut self, strategy: RoundingStrategy) -> Self {
+        self.input_rounding = Some(strategy);
+        self
+    }
+
+    // Stops processing input after given number of rows is read, regardless
+    // of whether they were valid and applied. The limit counts rows of all
+    // inputs applied to the engine, not of each one. Useful for sampling
+    // a large file's prefix.
+    pub fn with_row_limit(mut self, limit: usize) -> Self {
+        self.row_limit = Some(limit);
+        self
+    }
+
+    // Processes only a pseudo-random sample of rows, each picked with
+    // probability `rate` between 0 and 1. The sample only depends on `seed`
+    // and row positions, so runs are reproducible. Results are approximate:
+    // e.g. disputes of deposits left out of the sample are rejected, so this
+    // is only meant for quick analytics of huge files, like estimating how
+    // transactions are distributed among clients.
+    pub fn with_sample_rate(mut self, rate: f64, seed: u64) -> Self {
+        self.sampling = Some(Sampling { rate, seed });
+        self
+    }
+
+    // Replaces client ids in the output with pseudonyms, the same for the same
+    // seed, and leaves external ids out; balances are unchanged. Rows are
+    // sorted by the pseudonyms. See `write_anonymization_map` for mapping them
+    // back to client ids.
+    pub fn with_anonymized_output(mut self, seed: u64) -> Self {
+        self.anonymize_seed = Some(seed);
+        self
+    }
+
+    // Client id as written in the output
+    fn output_id(&self, client: ClientId) -> ClientId {
+        match self.anonymize_seed {
+            Some(seed) => pseudonym(seed, client),
+            None => client,
+        }
+    }
+
+    pub fn with_header_policy(mut self, policy: HeaderPolicy) -> Self {
+        self.header_policy = policy;
+        self
+    }
+
+    // Prints balances as integers in minor units, preceded by a `# scale=4`
+    // comment line, for consumers which can't parse decimal strings safely
+    pub fn with_fixed_point_output(mut self) -> Self {
+        self.fixed_point_output = true;
+        self
+    }
+
+    // Appends a `lock_reason` column to the output, e.g. `chargeback:5` for
+    // a client locked by charging back transaction 5
+    pub fn with_lock_reason_column(mut self) -> Self {
+        self.lock_reason_column = true;
+        self
+    }
+
+    // Prints exactly the given columns, in the given order
+    pub fn with_output_fields(mut self, fields: Vec<OutputField>) -> Self {
+        self.output_fields = Some(fields);
+        self
+    }
+
+    // Sums up applied deposits and withdrawals of rows with a `timestamp`
+    // column, in seconds since the epoch, per time bucket of given size
+    pub fn with_volume_buckets(mut self, bucket_size: BucketSize) -> Self {
+        self.volume_buckets = Some(bucket_size);
+        self
+    }
+
+    // Records every applied dispute along with its outcome, for
+    // `write_disputes_report`
+    pub fn with_dispute_tracking(mut self) -> Self {
+        self.track_disputes = true;
+        self
+    }
+
+    // Reads input files as having no header row, with columns mapped
+    // positionally to type, client, tx and amount
+    pub fn with_no_header(mut self) -> Self {
+        self.headerless = true;
+        self
+    }
+
+    // Size in bytes of the buffer input files and streams are read through,
+    // for tuning throughput on high-latency storage
+    pub fn with_input_buffer_size(mut self, bytes: usize) -> Self {
+        self.input_buffer_size = Some(bytes);
+        self
+    }
+
+    // Maps non-standard input header names to the canonical ones (type, client,
+    // external_id, tx, amount), e.g. "customer" -> "client". Headers without
+    // an alias are used as is.
+    pub fn with_header_aliases(
+        mut self,
+        aliases: std::collections::HashMap<String, String>,
+    ) -> Self {
+        self.header_aliases = aliases.into_iter().collect();
+        self
+    }
+
+    // With disputes disabled, deposits and withdrawals aren't stored at all,
+    // which saves memory for inputs known to have no dispute-type rows. As a
+    // consequence, such rows are rejected and reused transaction ids aren't
+    // detected.
+    pub fn with_disputes_enabled(mut self, enabled: bool) -> Self {
+        self.disputes_disabled = !enabled;
+        self
+    }
+
+    // Streaming mode for inputs too large to keep every transaction in memory:
+    // only deposits are stored, since they're the only transactions which can
+    // be disputed, and they're dropped once their dispute is resolved or
+    // charged back. Disputes of withdrawals or of dropped deposits are rejected
+    // as referring to unknown transactions, and reused ids of dropped deposits
+    // aren't detected.
+    pub fn with_streaming(mut self) -> Self {
+        self.streaming = true;
+        self
+    }
+
+    // Keeps clients in a flat vector indexed by client id instead of a hash map,
+    // which speeds up lookups when client ids densely cover the u16 range, at
+    // the cost of memory proportional to the highest client id
+    pub fn with_dense_client_store(mut self) -> Self {
+        let mut clients = ClientStore::Dense(DenseClients::new());
+        for (id, info) in self.clients.iter() {
+            clients.insert(id, info.clone());
+        }
+        self.clients = clients;
+        self
+    }
+
+    // Rejects adjustments, for systems where balances may only change through
+    // regular transactions
+    pub fn with_adjustments_forbidden(mut self) -> Self {
+        self.adjustments_forbidden = true;
+        self
+    }
+
+    // Warns about input amounts with more than 4 decimal places, but still
+    // applies them rounded to 4 places, using the input rounding strategy
+    // if one is set, or banker's rounding otherwise
+    pub fn with_precision_warnings(mut self) -> Self {
+        self.precision_warnings = true;
+        self
+    }
+
+    // Accepts rows of a generic `transaction` type, which are deposits
+    // for positive amounts and withdrawals for negative ones. Other types are
+    // handled as usual, including the rejection of negative amounts.
+    pub fn with_signed_amounts(mut self) -> Self {
+        self.signed_amounts = true;
+        self
+    }
+
+    // Diagnostic mode which buffers all rows of a reader and applies them newest
+    // first, e.g. to trace how a balance was built. Operations referring to
+    // transactions which come earlier in the input, like disputes, or withdrawals
+    // of funds deposited earlier, are rejected with warnings in this mode.
+    pub fn with_reverse_order(mut self) -> Self {
+        self.reverse_order = true;
+        self
+    }
+
+    // Skips rows of all clients but the given ones right at ingestion, without
+    // warnings, so that only these clients are processed and printed
+    pub fn with_client_filter(mut self, clients: std::collections::HashSet<ClientId>) -> Self {
+        self.client_filter = Some(clients.into_iter().collect());
+        self
+    }
+
+    // Treats a deposit or withdrawal reusing the id of a fully settled transaction,
+    // i.e. one voided or charged back as a whole, as a new transaction instead of
+    // a duplicate, which handles the id space wrapping around in very long runs.
+    // Ids of transactions which still affect balances are rejected as usual.
+    pub fn with_tx_id_recycling(mut self, enabled: bool) -> Self {
+        self.recycle_tx_ids = enabled;
+        self
+    }
+
+    // Rejects deposits and withdrawals with a transaction id of 0, which is
+    // usually a sentinel or uninitialized value coming from a bad feed
+    pub fn with_zero_tx_id_rejection(mut self, enabled: bool) -> Self {
+        self.zero_tx_id_rejected = enabled;
+        self
+    }
+
+    pub fn with_dispute_source(mut self, source: DisputeSource) -> Self {
+        self.dispute_source = source;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: DisputePolicy) -> Self {
+        self.dispute_policy = policy;
+        self
+    }
+
+    // Every call reading an input, like `apply_csv_reader` or `apply_ndjson`,
+    // starts a new file for `DisputeScope::PerFile`
+    pub fn with_dispute_scope(mut self, scope: DisputeScope) -> Self {
+        self.dispute_scope = scope;
+        self
+    }
+
+    // Enforces non-negative held funds: disputes which would leave `held`
+    // negative or above total funds are rejected. This rules out disputes of
+    // withdrawals, and disputes overdrawing available funds with
+    // `DisputeSource::TotalFunds`.
+    pub fn with_held_invariant(mut self) -> Self {
+        self.held_invariant = true;
+        self
+    }
+
+    // Makes resolves and chargebacks verify that they move exactly the amount
+    // recorded by the dispute, instead of falling back to the full transaction
+    // amount when none was recorded, e.g. in state restored from an older
+    // snapshot. Diverging bookkeeping is rejected.
+    pub fn with_strict_dispute_amounts(mut self) -> Self {
+        self.strict_dispute_amounts = true;
+        self
+    }
+
+    // Rejects disputing a resolved transaction again until given number of
+    // other operations is processed after the resolve, rejected disputes included
+    pub fn with_resolve_cooldown(mut self, operations: u64) -> Self {
+        self.resolve_cooldown = Some(operations);
+        self
+    }
+
+    // Records the client's balances after every operation applied to it, in
+    // the order of application, see `trace`
+    pub fn with_client_trace(mut self, client: ClientId) -> Self {
+        self.traced_client = Some(client);
+        self
+    }
+
+    // Writes every applied operation to `writer` as soon as it's applied, as CSV
+    // rows in the input format. Rejected rows are left out, so replaying the log
+    // into an engine configured the same way reproduces the state exactly.
+    // A header is written first if `header` is set; it should be left out when
+    // appending to an existing log. See `finish_operation_log`.
+    pub fn with_operation_log<W: Write + Send + 'static>(
+        mut self,
+        writer: W,
+        header: bool,
+    ) -> Self {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let mut log = OperationLog {
+            writer: csv::WriterBuilder::new().from_writer(writer),
+            error: None,
+        };
+        if header {
+            if let Err(e) = log
+                .writer
+                .write_record(["type", "client", "tx", "amount", "note", "target"])
+            {
+                log.error = Some(e.into());
+            }
+        }
+        self.operation_log = Some(log);
+        self
+    }
+
+    // Flushes the operation log and reports the first error hit while writing it,
+    // if any; writing stops after an error, while processing goes on
+    pub fn finish_operation_log(&mut self) -> io::Result<()> {
+        match &mut self.operation_log {
+            Some(log) => match log.error.take() {
+                Some(e) => Err(e),
+                None => log.writer.flush(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    // Locks a client once it has attempted to dispute its own already disputed
+    // transactions given number of times, as a sign of abuse. The attempts are
+    // rejected as `AlreadyDisputed` either way.
+    pub fn with_redispute_lock(mut self, attempts: u32) -> Self {
+        self.redispute_limit = Some(attempts);
+        self
+    }
+
+    // Appends a `hash` column to the output, with a hex-encoded HMAC-SHA256
+    // of the row's `client,available,held,total,locked` fields under given key
+    #[cfg(feature = "row-hash")]
+    pub fn with_row_hash_key(mut self, key: &[u8]) -> Self {
+        self.row_hash_key = Some(key.to_vec());
+        self
+    }
+
+    // Performance note: this validation uses extra lookups in the transactions and
+    // client maps, but also makes the code clearer to read. In case of a bottleneck,
+    // all checks can be inlined to respective functions.
+    fn valid(&self, op: &Operation) -> Result<(), WarningKind> {
+        let (tx, client) = (op.tx(), op.client());
+        // Unlocks don't refer to transactions, and locked clients are the only
+        // ones they apply to
+        if let Operation::Unlock { .. } = op {
+            return match self.clients.get(&client) {
+                Some(info) if info.locked => Ok(()),
+                Some(_) => Err(WarningKind::ClientNotLocked(client)),
+                None => Err(WarningKind::ClientNotFound(client)),
+            };
+        }
+        let should_exist: bool = !matches!(
+            op,
+            Operation::Deposit { .. }
+                | Operation::Withdrawal { .. }
+                | Operation::Adjustment { .. }
+                | Operation::Transfer { .. }
+        );
+        // Amends of transactions which weren't stored are rejected as not found
+        let amend = matches!(op, Operation::Amend { .. });
+        if should_exist && self.disputes_disabled && !amend {
+            return Err(WarningKind::DisputesDisabled(tx));
+        }
+        if self.zero_tx_id_rejected
+            && tx == 0
+            && matches!(op, Operation::Deposit { .. } | Operation::Withdrawal { .. })
+        {
+            return Err(WarningKind::ZeroTxId);
+        }
+        if let Operation::Adjustment { .. } = op {
+            if self.adjustments_forbidden {
+                return Err(WarningKind::AdjustmentsForbidden(tx));
+            }
+        }
+        // Adjustments share the id space with transactions, but can't be disputed
+        if !should_exist && self.adjustments.contains_key(&tx) {
+            return Err(WarningKind::TransactionExists(tx));
+        }
+        // Recycled ids are only forgotten once the new transaction is applied
+        let recycled = matches!(op, Operation::Deposit { .. } | Operation::Withdrawal { .. })
+            && self.recyclable(tx);
+        match self.transactions.get(&tx) {
+            Some(_) if !should_exist && !recycled => {
+                return Err(WarningKind::TransactionExists(tx))
+            }
+            None if should_exist => return Err(WarningKind::TransactionNotFound(tx)),
+            _ => (),
+        }
+        // A dispute, resolve or chargeback of a client never seen before can't
+        // refer to any of its transactions
+        let disputing = matches!(
+            op,
+            Operation::Dispute { .. } | Operation::Resolve { .. } | Operation::Chargeback { .. }
+        );
+        if disputing && !self.clients.contains_key(&client) {
+            return Err(WarningKind::ClientNotFound(client));
+        }
+        if let Operation::Deposit { amount, .. }
+        | Operation::Withdrawal { amount, .. }
+        | Operation::Adjustment { amount, .. }
+        | Operation::Amend { amount, .. }
+        | Operation::Transfer { amount, .. } = op
+        {
+            let signed = matches!(op, Operation::Adjustment { .. });
+            if amount.is_sign_negative() && !signed {
+                return Err(WarningKind::NegativeAmount(*amount));
+            }
+            // Amounts are rounded before this check if input rounding or
+            // precision warnings are enabled
+            let scale = decimal_places(*amount);
+            if scale > DECIMAL_PLACES {
+                return Err(WarningKind::TooManyDecimalPlaces { tx, scale });
+            }
+        }
+        if let Some(info) = self.clients.get(&client) {
+            if info.locked {
+                return Err(WarningKind::ClientLocked(client));
+            }
+        }
+        // Checked here so that a dispute never marks, and a resolve or
+        // chargeback never releases, another client's transaction
+        if disputing && self.transactions.get(&tx).map(|tx_entry| tx_entry.client) != Some(client) {
+            return Err(WarningKind::ClientMismatch { tx, client });
+        }
+        if let Operation::Transfer { target, .. } = *op {
+            if target == client {
+                return Err(WarningKind::SelfTransfer(tx));
+            }
+            if self.clients.get(&target).is_some_and(|info| info.locked) {
+                return Err(WarningKind::ClientLocked(target));
+            }
+        }
+        Ok(())
+    }
+
+    // Validates and applies a single operation; invalid operations are skipped
+    // with a warning
+    pub fn apply(&mut self, op: Operation) {
+        self.apply_at(op, None, None);
+    }
+
+    // Applies operations received from the channel until all senders are
+    // dropped, like `apply` does. Created with `mpsc::sync_channel`, the
+    // channel's bound makes producers block while the engine falls behind.
+    // Sharding only partitions the output (see `to_sharded_csv`), so a single
+    // engine consumes operations of all clients; order is kept per sender.
+    pub fn apply_channel(&mut self, receiver: mpsc::Receiver<Operation>) {
+        for op in receiver {
+            self.apply(op);
+        }
+    }
+
+    // Returns whether the operation was applied
+    fn apply_at(&mut self, op: Operation, line: Option<u64>, note: Option<&str>) -> bool {
+        match self.apply_noted(op, note) {
+            Ok(outcome) => {
+                if outcome.lost_precision {
+                    let (tx, client) = (op.tx(), op.client());
+                    self.warn(line, WarningKind::PrecisionLoss { tx, client });
+                }
+                true
+            }
+            Err(reason) => {
+                self.warn(line, reason.into());
+                false
+            }
+        }
+    }
+
+    // Validates and applies a single operation, returning its effect on the client's
+    // balances, or the reason for rejecting it. Unlike `apply`, rejections are
+    // not recorded as warnings.
+    pub fn apply_operation(&mut self, op: Operation) -> Result<OpOutcome, EngineError> {
+        self.apply_noted(op, None)
+    }
+
+    // Applies an operation along with the audit note of adjustments
+    fn apply_noted(&mut self, op: Operation, note: Option<&str>) -> Result<OpOutcome, EngineError> {
+        self.valid(&op)?;
+        let client = op.client();
+        self.seq += 1;
+        self.touch(client, Some(op.tx()));
+        let zero: Amount = 0.into();
+        let balances = |engine: &Self| {
+            engine
+                .clients
+                .get(&client)
+                .map_or((zero, zero, false), |info| {
+                    (info.available, info.held, info.locked)
+                })
+        };
+        let (available, held, _) = balances(self);
+        self.lost_precision = false;
+        match op {
+            Operation::Deposit { tx, client, amount } => self.deposit(tx, client, amount),
+            Operation::Withdrawal { tx, client, amount } => self.withdraw(tx, client, amount),
+            Operation::Dispute { tx, client } => self.dispute(tx, client),
+            Operation::Resolve { tx, client } => self.resolve(tx, client),
+            Operation::Chargeback { tx, client } => self.chargeback(tx, client),
+            Operation::Void { tx, client } => self.void(tx, client),
+            Operation::Refund { tx, client } => self.refund(tx, client),
+            Operation::Adjustment { tx, client, amount } => self.adjust(tx, client, amount),
+            Operation::Unlock { client, .. } => self.unlock(client),
+            Operation::Amend { tx, client, amount } => self.amend(tx, client, amount),
+            Operation::Transfer {
+                tx,
+                client,
+                target,
+                amount,
+            } => self.transfer(tx, client, target, amount),
+        }?;
+        if let Some(note) = note {
+            self.annotate(op.tx(), note);
+        }
+        if let Some(log) = &mut self.operation_log {
+            log.append(&op, note);
+        }
+        if self.dispute_scope == DisputeScope::PerFile {
+            if let Operation::Deposit { tx, .. }
+            | Operation::Withdrawal { tx, .. }
+            | Operation::Transfer { tx, .. } = op
+            {
+                self.input_txs.insert(tx);
+            }
+        }
+        *self.op_counts.entry(client).or_insert(0) += 1;
+        let (new_available, new_held, locked) = balances(self);
+        if self.traced_client == Some(client) {
+            self.trace.push(TracePoint {
+                op: op.name().to_string(),
+                tx: op.tx(),
+                available: new_available,
+                held: new_held,
+            });
+        }
+        Ok(OpOutcome {
+            client,
+            available_delta: new_available - available,
+            held_delta: new_held - held,
+            locked,
+            lost_precision: self.lost_precision,
+        })
+    }
+
+    // Whether the transaction no longer contributes to any balance
+    fn settled(&self, tx: TxId) -> bool {
+        let tx_entry = match self.transactions.get(&tx) {
+            Some(tx_entry) => tx_entry,
+            None => return false,
+        };
+        // The source's side of a transfer stays, so its id can't be reused
+        if self.transfer_sources.contains_key(&tx) {
+            return false;
+        }
+        self.voided.contains(&tx)
+            || (self.charged_back.contains(&tx)
+                && self.dispute_amount(tx, tx_entry) == tx_entry.amount)
+    }
+
+    // Returns whether a new deposit or withdrawal may reuse the transaction's id
+    fn recyclable(&self, tx: TxId) -> bool {
+        self.recycle_tx_ids && self.settled(tx)
+    }
+
+    // Drops all state of a settled transaction, so that its id can be reused
+    fn forget(&mut self, tx: TxId) {
+        if let Some(tx_entry) = self.transactions.remove(&tx) {
+            if let Some(deposits) = self.remaining.get_mut(&tx_entry.client) {
+                deposits.remove(&tx);
+            }
+        }
+        self.disputed.remove(&tx);
+        self.charged_back.remove(&tx);
+        self.voided.remove(&tx);
+        self.refunded.remove(&tx);
+        self.transfer_sources.remove(&tx);
+        self.resolved_at.remove(&tx);
+        self.dispute_amounts.remove(&tx);
+        self.draws.remove(&tx);
+    }
+
+    // Records that the client and transaction were potentially modified
+    // at the current sequence number
+    fn touch(&mut self, client: ClientId, tx: Option<TxId>) {
+        if !self.track_changes {
+            return;
+        }
+        self.client_seqs.insert(client, self.seq);
+        if let Some(tx) = tx {
+            self.tx_seqs.insert(tx, self.seq);
+        }
+    }
+
+    fn warn(&mut self, line: Option<u64>, kind: WarningKind) {
+        let warning = self.record_warning(line, kind);
+        log::warn!("{}", warning);
+    }
+
+    fn record_warning(&mut self, line: Option<u64>, kind: WarningKind) -> &Warning {
+        let warning = Warning {
+            seq: self.next_warning_seq,
+            line,
+            kind,
+        };
+        self.next_warning_seq += 1;
+        *self
+            .warning_counts
+            .entry(warning.kind.name().to_string())
+            .or_insert(0) += 1;
+        self.warnings.push(warning);
+        &self.warnings[self.warnings.len() - 1]
+    }
+
+    // Up to `k` clients with the most applied operations, most active first;
+    // ties are broken by client id
+    pub fn top_clients(&self, k: usize) -> Vec<(ClientId, u64)> {
+        let mut counts: Vec<(ClientId, u64)> = self
+            .op_counts
+            .iter()
+            .map(|(client, count)| (*client, *count))
+            .collect();
+        counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        counts.truncate(k);
+        counts
+    }
+
+    // Number of CSV rows read so far, including rejected and malformed ones
+    pub fn rows_read(&self) -> u64 {
+        self.rows_read
+    }
+
+    // Number of rows which can still be read before reaching the row limit
+    fn rows_left(&self) -> usize {
+        self.row_limit.map_or(usize::MAX, |limit| {
+            limit.saturating_sub(self.rows_read as usize)
+        })
+    }
+
+    // Number of warnings of each kind issued so far, keyed by kind name;
+    // unlike `warnings`, not reset by draining
+    pub fn warning_counts(&self) -> &HashMap<String, usize> {
+        &self.warning_counts
+    }
+
+    // Returns warnings accumulated so far
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    // Takes all accumulated warnings, leaving the buffer empty. Long-running users
+    // should drain warnings periodically to keep memory usage bounded.
+    pub fn drain_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    // Deposits funds
+    fn deposit(&mut self, tx: TxId, client: ClientId, amount: Amount) -> Result<(), EngineError> {
+        self.check_overflow(tx, client, amount)?;
+        if self.recyclable(tx) {
+            self.forget(tx);
+        }
+        if !self.disputes_disabled {
+            self.transactions.insert(tx, Transaction { client, amount });
+            self.remaining.entry(client).or_default().insert(tx, amount);
+        }
+
+        if let Some(info) = self.clients.get_mut(&client) {
+            self.lost_precision |= !add_exact(&mut info.available, amount);
+        } else {
+            self.clients.insert(client, ClientInfo::new(amount));
+        }
+        Ok(())
+    }
+
+    // Rejects adding the amount to the client's available funds if either they
+    // or total funds wouldn't fit in the amount type. Withdrawals and disputes
+    // only move funds around within the total, so with the total in range,
+    // deposits and adjustments are the only operations which could overflow.
+    fn check_overflow(
+        &self,
+        tx: TxId,
+        client: ClientId,
+        amount: Amount,
+    ) -> Result<(), WarningKind> {
+        let (available, held) = self
+            .clients
+            .get(&client)
+            .map_or((zero(), zero()), |info| (info.available, info.held));
+        match checked_add(available, amount).and_then(|available| checked_add(available, held)) {
+            Some(_) => Ok(()),
+            None => Err(WarningKind::Overflow { tx, client }),
+        }
+    }
+
+    // Withdraws funds if possible; the operation is ignored if no sufficient
+    // funds are available. A rejected withdrawal is forgotten altogether, so its
+    // transaction id remains free to use.
+    fn withdraw(&mut self, tx: TxId, client: ClientId, amount: Amount) -> Result<(), EngineError> {
+        let requested = amount;
+        let mut amount = amount;
+        amount.set_sign_negative(true);
+        let available = match self.clients.get(&client) {
+            Some(info) => info.available,
+            None => return Err(WarningKind::ClientNotFound(client).into()),
+        };
+        if available + amount < zero() {
+            return Err(EngineError::InsufficientFunds {
+                client,
+                requested,
+                available,
+            });
+        }
+        if self.recyclable(tx) {
+            self.forget(tx);
+        }
+        if !self.disputes_disabled && !self.streaming {
+            self.transactions.insert(tx, Transaction { client, amount });
+        }
+        if let Some(info) = self.clients.get_mut(&client) {
+            self.lost_precision |= !add_exact(&mut info.available, amount);
+        }
+        self.draw_down(client, tx, requested);
+        Ok(())
+    }
+
+    // Moves available funds of the client to the target, creating the target
+    // if needed. The source's funds are drawn from its deposits like with
+    // a withdrawal, while the target can dispute the transfer like a deposit.
+    fn transfer(
+        &mut self,
+        tx: TxId,
+        client: ClientId,
+        target: ClientId,
+        amount: Amount,
+    ) -> Result<(), EngineError> {
+        let available = match self.clients.get(&client) {
+            Some(info) => info.available,
+            None => return Err(WarningKind::ClientNotFound(client).into()),
+        };
+        if available < amount {
+            return Err(EngineError::InsufficientFunds {
+                client,
+                requested: amount,
+                available,
+            });
+        }
+        self.check_overflow(tx, target, amount)?;
+        if let Some(info) = self.clients.get_mut(&client) {
+            self.lost_precision |= !sub_exact(&mut info.available, amount);
+        }
+        self.draw_down(client, tx, amount);
+        // Draws are only given back by withdrawal disputes
+        self.draws.remove(&tx);
+        if !self.disputes_disabled {
+            let transaction = Transaction {
+                client: target,
+                amount,
+            };
+            self.transactions.insert(tx, transaction);
+            self.transfer_sources.insert(tx, client);
+            self.remaining.entry(target).or_default().insert(tx, amount);
+        }
+        if let Some(info) = self.clients.get_mut(&target) {
+            self.lost_precision |= !add_exact(&mut info.available, amount);
+        } else {
+            self.clients.insert(target, ClientInfo::new(amount));
+        }
+        self.touch(target, Some(tx));
+        Ok(())
+    }
+
+    // Draws a withdrawn amount from the client's undisputed deposits, oldest
+    // transaction ids first; fully drawn deposits can no longer be disputed
+    fn draw_down(&mut self, client: ClientId, withdrawal: TxId, mut amount: Amount) {
+        let zero = zero();
+        let mut drawn = Vec::new();
+        let disputed = &self.disputed;
+        if let Some(deposits) = self.remaining.get_mut(&client) {
+            // Stops at the first deposit which covers the rest, so that bursts
+            // of a single client's rows don't scan all of its deposits each time
+            let mut emptied = Vec::new();
+            for (tx, left) in deposits.iter_mut() {
+                if amount <= zero {
+                    break;
+                }
+                if disputed.contains(tx) {
+                    continue;
+                }
+                let drawn_from_tx = if *left < amount { *left } else { amount };
+                *left -= drawn_from_tx;
+                amount -= drawn_from_tx;
+                drawn.push((*tx, drawn_from_tx));
+                if *left <= zero {
+                    emptied.push(*tx);
+                }
+            }
+            for tx in emptied {
+                deposits.remove(&tx);
+            }
+        }
+        for (tx, _) in &drawn {
+            self.touch(client, Some(*tx));
+        }
+        // Draws are only needed for disputing the withdrawal
+        if !self.streaming {
+            self.draws.insert(withdrawal, drawn);
+        }
+    }
+
+    // Gives amounts drawn by a disputed withdrawal back to the deposits
+    fn undraw(&mut self, client: ClientId, withdrawal: TxId) {
+        for (tx, amount) in self.draws.remove(&withdrawal).unwrap_or_default() {
+            if self.charged_back.contains(&tx) || self.voided.contains(&tx) {
+                continue;
+            }
+            let deposits = self.remaining.entry(client).or_default();
+            *deposits.entry(tx).or_insert_with(|| 0.into()) += amount;
+            self.touch(client, Some(tx));
+        }
+    }
+
+    // Part of a deposit which can still be disputed
+    fn remaining(&self, client: ClientId, tx: TxId) -> Amount {
+        self.remaining
+            .get(&client)
+            .and_then(|deposits| deposits.get(&tx))
+            .copied()
+            .unwrap_or_else(|| 0.into())
+    }
+
+    // Handles a dispute, moving funds into `held`. Only the part of a deposit
+    // which wasn't withdrawn yet is held.
+    // NOTE: disputing a withdrawal is not specified, so the semantics
+    // are assumed as follows: it's legal to dispute, resolve and chargeback
+    // withdrawals, but available funds may never go below zero, or the operation
+    // is dropped as invalid. Alternatively, withdrawal disputes could be banned,
+    // which is trivial to validate.
+    fn dispute(&mut self, tx: TxId, client: ClientId) -> Result<(), EngineError> {
+        if self.disputed.contains(&tx) {
+            self.count_redispute(tx, client);
+            return Err(EngineError::AlreadyDisputed(tx));
+        }
+        // Charged back transactions are final
+        if self.charged_back.contains(&tx) {
+            return Err(WarningKind::TransactionChargedBack(tx).into());
+        }
+        if self.voided.contains(&tx) {
+            return Err(self.voided_warning(tx).into());
+        }
+        if let (Some(cooldown), Some(resolved_at)) =
+            (self.resolve_cooldown, self.resolved_at.get(&tx))
+        {
+            // Operations applied in between, not counting this dispute
+            let elapsed = self.seq - resolved_at - 1;
+            if elapsed < cooldown {
+                return Err(WarningKind::ResolveCooldown {
+                    tx,
+                    remaining: cooldown - elapsed,
+                }
+                .into());
+            }
+        }
+        if let Some(tx_entry) = self.transactions.get(&tx).copied() {
+            if tx_entry.amount.is_sign_negative()
+                && self.dispute_policy == DisputePolicy::DepositsOnly
+            {
+                return Err(WarningKind::WithdrawalDisputeDisallowed(tx).into());
+            }
+            if self.dispute_scope == DisputeScope::PerFile && !self.input_txs.contains(&tx) {
+                return Err(WarningKind::DisputeOutsideFile(tx).into());
+            }
+            // Normally a stored transaction's client exists, but nothing should be
+            // marked disputed without funds actually being held
+            if !self.clients.contains_key(&client) {
+                return Err(WarningKind::ClientNotFound(client).into());
+            }
+            let mut amount = tx_entry.amount;
+            if !amount.is_sign_negative() {
+                let remaining = self.remaining(client, tx);
+                if remaining == zero() {
+                    return Err(WarningKind::FullyWithdrawn(tx).into());
+                }
+                if remaining < amount {
+                    amount = remaining;
+                }
+            }
+            if let Some(info) = self.clients.get_mut(&client) {
+                let funds = match self.dispute_source {
+                    DisputeSource::AvailableOnly => info.available,
+                    DisputeSource::TotalFunds => info.total(),
+                };
+                // Disputing a withdrawal gives its funds back to `available`
+                // and turns `held` negative, so there's nothing to cover
+                if !amount.is_sign_negative() && amount > funds {
+                    return Err(WarningKind::DisputeExceedsAvailable {
+                        tx,
+                        amount,
+                        available: funds,
+                    }
+                    .into());
+                }
+                // Disputes move funds between available and held, so the
+                // total stays the same
+                let (held, total) = (info.held + amount, info.total());
+                if self.held_invariant && (held < zero() || held > total) {
+                    return Err(WarningKind::HeldOutOfBounds { tx, held, total }.into());
+                }
+                // Only a dispute which holds funds marks the transaction disputed
+                self.disputed.insert(tx);
+                self.lost_precision |= !sub_exact(&mut info.available, amount);
+                self.lost_precision |= !add_exact(&mut info.held, amount);
+                self.dispute_amounts.insert(tx, amount);
+                self.log_dispute(tx, client, amount);
+                if amount.is_sign_negative() {
+                    self.undraw(client, tx);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Resolves a dispute, moving funds from `held` back into `available`
+    fn resolve(&mut self, tx: TxId, client: ClientId) -> Result<(), EngineError> {
+        if self.charged_back.contains(&tx) {
+            return Err(WarningKind::TransactionChargedBack(tx).into());
+        }
+        if !self.disputed.contains(&tx) {
+            return Err(WarningKind::ResolveNotDisputed(tx).into());
+        }
+
+        if let Some(tx_entry) = self.transactions.get(&tx).copied() {
+            let amount = self.dispute_amount(tx, &tx_entry);
+            if self.strict_dispute_amounts {
+                self.check_release(tx, client)?;
+            }
+            if let Some(info) = self.clients.get_mut(&client) {
+                if amount.is_sign_negative() {
+                    // Resolving a withdrawal's dispute takes the funds given
+                    // back by the dispute out of `available` again, which
+                    // mustn't turn negative, e.g. if they were withdrawn since
+                    if info.available + amount < zero() {
+                        return Err(EngineError::InsufficientFunds {
+                            client,
+                            requested: amount.abs(),
+                            available: info.available,
+                        });
+                    }
+                } else if amount > info.held {
+                    return Err(WarningKind::ExceedsHeld {
+                        tx,
+                        amount,
+                        held: info.held,
+                    }
+                    .into());
+                }
+                // A rejected resolve leaves the dispute open
+                self.disputed.remove(&tx);
+                self.lost_precision |= !add_exact(&mut info.available, amount);
+                self.lost_precision |= !sub_exact(&mut info.held, amount);
+                self.dispute_amounts.remove(&tx);
+                self.close_dispute(tx, DisputeOutcome::Resolved);
+                if amount.is_sign_negative() {
+                    self.draw_down(client, tx, amount.abs());
+                }
+            }
+        }
+        if self.resolve_cooldown.is_some() {
+            self.resolved_at.insert(tx, self.seq);
+        }
+        self.prune(tx);
+        Ok(())
+    }
+
+    // In streaming mode, drops a transaction whose dispute is over. The rest
+    // of a resolved deposit is still drawn down by withdrawals as usual.
+    fn prune(&mut self, tx: TxId) {
+        if !self.streaming {
+            return;
+        }
+        self.transactions.remove(&tx);
+        self.transfer_sources.remove(&tx);
+        self.charged_back.remove(&tx);
+        self.resolved_at.remove(&tx);
+        self.dispute_amounts.remove(&tx);
+    }
+
+    fn count_redispute(&mut self, tx: TxId, client: ClientId) {
+        let limit = match self.redispute_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        // Others' transactions don't count, so that nobody gets locked by
+        // disputes of a different client
+        if self.transactions.get(&tx).map(|tx_entry| tx_entry.client) != Some(client) {
+            return;
+        }
+        let attempts = self.redisputes.entry(client).or_insert(0);
+        *attempts += 1;
+        if *attempts < limit {
+            return;
+        }
+        if let Some(info) = self.clients.get_mut(&client) {
+            if !info.locked {
+                info.locked = true;
+                info.lock_reason = Some(LockReason::RepeatedDisputes { tx });
+            }
+        }
+    }
+
+    fn log_dispute(&mut self, tx: TxId, client: ClientId, amount: Amount) {
+        if !self.track_disputes {
+            return;
+        }
+        self.open_disputes.insert(tx, self.dispute_log.len());
+        self.dispute_log.push(DisputeRecord {
+            tx,
+            client,
+            amount,
+            outcome: DisputeOutcome::Open,
+        });
+    }
+
+    fn close_dispute(&mut self, tx: TxId, outcome: DisputeOutcome) {
+        if let Some(index) = self.open_disputes.remove(&tx) {
+            self.dispute_log[index].outcome = outcome;
+        }
+    }
+
+    // Verifies that releasing the dispute from `held` moves exactly
+    // the amount recorded when the transaction was disputed. What the client
+    // holds beyond its other open disputes belongs to this one, so it has to
+    // match the recorded amount unless the bookkeeping drifted. Scans the open
+    // disputes, which is why it's only done in strict mode.
+    fn check_release(&self, tx: TxId, client: ClientId) -> Result<(), WarningKind> {
+        let disputed = *self
+            .dispute_amounts
+            .get(&tx)
+            .ok_or(WarningKind::DisputeAmountUnknown(tx))?;
+        let mut moved = self.clients.get(&client).map_or(zero(), |info| info.held);
+        for other in &self.disputed {
+            match self.transactions.get(other) {
+                Some(tx_entry) if *other != tx && tx_entry.client == client => {
+                    moved -= self.dispute_amount(*other, tx_entry);
+                }
+                _ => (),
+            }
+        }
+        if moved != disputed {
+            return Err(WarningKind::DisputeAmountMismatch {
+                tx,
+                disputed,
+                moved,
+            });
+        }
+        Ok(())
+    }
+
+    // Amount held by the transaction's dispute
+    fn dispute_amount(&self, tx: TxId, tx_entry: &Transaction) -> Amount {
+        self.dispute_amounts
+            .get(&tx)
+            .copied()
+            .unwrap_or(tx_entry.amount)
+    }
+
+    // Charges back a dispute, removing funds from `held` and locking the account
+    fn chargeback(&mut self, tx: TxId, client: ClientId) -> Result<(), EngineError> {
+        if self.charged_back.contains(&tx) {
+            return Err(WarningKind::TransactionChargedBack(tx).into());
+        }
+        if !self.disputed.contains(&tx) {
+            return Err(WarningKind::ChargebackNotDisputed(tx).into());
+        }
+
+        if let Some(tx_entry) = self.transactions.get(&tx).copied() {
+            let amount = self.dispute_amount(tx, &tx_entry);
+            if self.strict_dispute_amounts {
+                self.check_release(tx, client)?;
+            }
+            if let Some(info) = self.clients.get_mut(&client) {
+                // Charging back a withdrawal's dispute only clears its
+                // negative held amount, the funds already being available
+                if !amount.is_sign_negative() && amount > info.held {
+                    return Err(WarningKind::ExceedsHeld {
+                        tx,
+                        amount,
+                        held: info.held,
+                    }
+                    .into());
+                }
+                self.disputed.remove(&tx);
+                self.lost_precision |= !sub_exact(&mut info.held, amount);
+                info.locked = true;
+                info.lock_reason = Some(LockReason::Chargeback { tx });
+                self.charged_back.insert(tx);
+                self.close_dispute(tx, DisputeOutcome::ChargedBack);
+                if let Some(deposits) = self.remaining.get_mut(&client) {
+                    deposits.remove(&tx);
+                }
+            }
+        }
+        self.prune(tx);
+        Ok(())
+    }
+
+    // Adjusts available funds by a signed amount, as long as they don't turn
+    // negative. Adjustments aren't transactions: they can't be disputed and
+    // don't count as deposits or withdrawals.
+    fn adjust(&mut self, tx: TxId, client: ClientId, amount: Amount) -> Result<(), EngineError> {
+        self.check_overflow(tx, client, amount)?;
+        let info = self
+            .clients
+            .get_mut(&client)
+            .ok_or(WarningKind::ClientNotFound(client))?;
+        if info.available + amount < zero() {
+            return Err(EngineError::InsufficientFunds {
+                client,
+                requested: amount.abs(),
+                available: info.available,
+            });
+        }
+        self.lost_precision |= !add_exact(&mut info.available, amount);
+        let note = String::new();
+        self.adjustments.insert(
+            tx,
+            Adjustment {
+                client,
+                amount,
+                note,
+            },
+        );
+        Ok(())
+    }
+
+    // Applies an adjustment with an audit note, see `Operation::Adjustment`
+    pub fn apply_adjustment(
+        &mut self,
+        tx: TxId,
+        client: ClientId,
+        amount: Amount,
+        note: &str,
+    ) -> Result<OpOutcome, EngineError> {
+        self.apply_noted(Operation::Adjustment { tx, client, amount }, Some(note))
+    }
+
+    fn annotate(&mut self, tx: TxId, note: &str) {
+        if let Some(adjustment) = self.adjustments.get_mut(&tx) {
+            adjustment.note = note.to_string();
+        }
+    }
+
+    // Applied adjustments, ordered by id
+    pub fn adjustments(&self) -> &BTreeMap<TxId, Adjustment> {
+        &self.adjustments
+    }
+
+    // Voids a deposit whose funds are still available, removing them from `available`.
+    // Unlike a chargeback, nothing is held and the account isn't locked. Voided
+    // deposits can't be disputed later.
+    fn void(&mut self, tx: TxId, client: ClientId) -> Result<(), EngineError> {
+        if self.voided.contains(&tx) {
+            return Err(self.voided_warning(tx).into());
+        }
+        if self.disputed.contains(&tx) {
+            return Err(EngineError::AlreadyDisputed(tx));
+        }
+        if self.charged_back.contains(&tx) {
+            return Err(WarningKind::TransactionChargedBack(tx).into());
+        }
+        let tx_entry = match self.transactions.get(&tx) {
+            Some(tx_entry) => tx_entry,
+            None => return Err(EngineError::TransactionNotFound(tx)),
+        };
+        if tx_entry.client != client {
+            return Err(EngineError::ClientMismatch { tx, client });
+        }
+        if tx_entry.amount.is_sign_negative() || self.transfer_sources.contains_key(&tx) {
+            return Err(WarningKind::NotADeposit(tx).into());
+        }
+        let info = match self.clients.get_mut(&client) {
+            Some(info) => info,
+            None => return Err(WarningKind::ClientNotFound(client).into()),
+        };
+        if tx_entry.amount > info.available {
+            return Err(EngineError::InsufficientFunds {
+                client,
+                requested: tx_entry.amount,
+                available: info.available,
+            });
+        }
+        self.lost_precision |= !sub_exact(&mut info.available, tx_entry.amount);
+        self.voided.insert(tx);
+        if let Some(deposits) = self.remaining.get_mut(&client) {
+            deposits.remove(&tx);
+        }
+        Ok(())
+    }
+
+    // Changes the amount of a deposit to `amount`, adding the difference to
+    // available funds. Like voids, amends only apply to deposits which aren't
+    // disputed, charged back or voided, and a deposit can't be lowered by more
+    // than the part of it which wasn't drawn by withdrawals yet.
+    fn amend(&mut self, tx: TxId, client: ClientId, amount: Amount) -> Result<(), EngineError> {
+        if self.voided.contains(&tx) {
+            return Err(self.voided_warning(tx).into());
+        }
+        if self.disputed.contains(&tx) {
+            return Err(EngineError::AlreadyDisputed(tx));
+        }
+        if self.charged_back.contains(&tx) {
+            return Err(WarningKind::TransactionChargedBack(tx).into());
+        }
+        let tx_entry = match self.transactions.get(&tx) {
+            Some(tx_entry) => *tx_entry,
+            None => return Err(EngineError::TransactionNotFound(tx)),
+        };
+        if tx_entry.client != client {
+            return Err(EngineError::ClientMismatch { tx, client });
+        }
+        if tx_entry.amount.is_sign_negative() || self.transfer_sources.contains_key(&tx) {
+            return Err(WarningKind::NotADeposit(tx).into());
+        }
+        let available = match self.clients.get(&client) {
+            Some(info) => info.available,
+            None => return Err(WarningKind::ClientNotFound(client).into()),
+        };
+        let delta = amount - tx_entry.amount;
+        let left = self
+            .remaining
+            .get(&client)
+            .and_then(|deposits| deposits.get(&tx))
+            .copied()
+            .unwrap_or_else(zero);
+        if delta.is_sign_negative() {
+            let available = if left < available { left } else { available };
+            if delta.abs() > available {
+                return Err(EngineError::InsufficientFunds {
+                    client,
+                    requested: delta.abs(),
+                    available,
+                });
+            }
+        } else {
+            self.check_overflow(tx, client, delta)?;
+        }
+        if let Some(info) = self.clients.get_mut(&client) {
+            self.lost_precision |= !add_exact(&mut info.available, delta);
+        }
+        self.transactions.insert(tx, Transaction { client, amount });
+        let deposits = self.remaining.entry(client).or_default();
+        if left + delta > zero() {
+            deposits.insert(tx, left + delta);
+        } else {
+            deposits.remove(&tx);
+        }
+        Ok(())
+    }
+
+    // Refunds a deposit, e.g. on the merchant's initiative: its funds leave
+    // `available` like with a void, and the deposit is marked refunded
+    fn refund(&mut self, tx: TxId, client: ClientId) -> Result<(), EngineError> {
+        self.void(tx, client)?;
+        self.refunded.insert(tx);
+        Ok(())
+    }
+
+    // Rejection of operations on a voided deposit, telling refunds apart
+    fn voided_warning(&self, tx: TxId) -> WarningKind {
+        if self.refunded.contains(&tx) {
+            WarningKind::TransactionRefunded(tx)
+        } else {
+            WarningKind::TransactionVoided(tx)
+        }
+    }
+
+    pub fn from_csv_reader<R: std::io::Read>(reader: csv::Reader<R>) -> Result<Self, EngineError> {
+        let mut engine = Self::new();
+        engine.apply_csv_reader(reader)?;
+        Ok(engine)
+    }
+
+    // Applies rows of each client on a rayon thread pool, keeping their order
+    // within each client, and merges the results. Clients connected by transfers
+    // are applied together, and other operations only ever touch a single client,
+    // so the outcome matches `from_csv_reader` as long as transaction ids are
+    // unique across clients: e.g. a dispute of another client's transaction is
+    // rejected as unknown here, instead of as a client mismatch. Rows are read
+    // and parsed up front, on the calling thread.
+    #[cfg(feature = "rayon")]
+    pub fn from_csv_parallel<R: std::io::Read>(
+        mut reader: csv::Reader<R>,
+    ) -> Result<Self, EngineError> {
+        use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+        let mut engine = Self::new();
+        let headers = reader.headers()?.clone();
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            engine.rows_read += 1;
+            if let Ok(record) = &record {
+                if record.iter().all(|field| field.trim().is_empty()) {
+                    continue;
+                }
+            }
+            let row = record.and_then(|record| {
+                let line = record.position().map(|position| position.line());
+                record
+                    .deserialize::<Row>(Some(&headers))
+                    .map(|row| (row, line))
+            });
+            match row {
+                // External ids are mapped to client ids here, so that they're
+                // assigned in input order
+                Ok((mut row, line)) => match engine.resolve_client(&row) {
+                    Ok(client) => {
+                        row.client = Some(client);
+                        row.external_id = None;
+                        rows.push((client, row, line));
+                    }
+                    Err(kind) => engine.warn(line, kind),
+                },
+                Err(e) if e.is_io_error() => return Err(e.into()),
+                Err(e) => {
+                    let line = e.position().map(|position| position.line());
+                    engine.warn(line, WarningKind::InvalidRow(e.to_string()));
+                }
+            }
+        }
+        // Each group of clients connected by transfers is keyed by its lowest id
+        let mut groups: HashMap<ClientId, ClientId> = HashMap::default();
+        let group = |groups: &HashMap<ClientId, ClientId>, mut client: ClientId| {
+            while let Some(lower) = groups.get(&client) {
+                client = *lower;
+            }
+            client
+        };
+        for (client, row, _) in &rows {
+            if let (OperationType::Transfer, Some(target)) = (row.op, row.target) {
+                let (source, target) = (group(&groups, *client), group(&groups, target));
+                if source != target {
+                    groups.insert(source.max(target), source.min(target));
+                }
+            }
+        }
+        let mut buckets: HashMap<ClientId, Vec<(Row, Option<u64>)>> = HashMap::default();
+        for (client, row, line) in rows {
+            let key = group(&groups, client);
+            buckets.entry(key).or_default().push((row, line));
+        }
+        let engines: Vec<Self> = buckets
+            .into_par_iter()
+            .map(|(_, rows)| {
+                let mut engine = Self::new();
+                for (row, line) in rows {
+                    engine.apply_row(row, line);
+                }
+                engine
+            })
+            .collect();
+        let mut warnings = Vec::new();
+        for partial in engines {
+            engine.apply_delta(&partial.snapshot());
+            for (client, count) in partial.op_counts {
+                *engine.op_counts.entry(client).or_insert(0) += count;
+            }
+            warnings.extend(partial.warnings);
+        }
+        // Already logged by the engine which applied the row
+        warnings.sort_by_key(|warning| (warning.line.is_none(), warning.line));
+        for warning in warnings {
+            engine.record_warning(warning.line, warning.kind);
+        }
+        debug_assert!(
+            engine.inconsistent_totals().is_empty(),
+            "Total funds don't match applied transactions for clients {:?}",
+            engine.inconsistent_totals()
+        );
+        Ok(engine)
+    }
+
+    // Applies all rows from the reader on top of the current state
+    pub fn apply_csv_reader<R: std::io::Read>(
+        &mut self,
+        mut reader: csv::Reader<R>,
+    ) -> Result<(), EngineError> {
+        self.input_txs.clear();
+        // Headerless input has the default columns in the default order
+        let headers = if reader.has_headers() {
+            reader.headers()?.clone()
+        } else {
+            csv::StringRecord::from(DEFAULT_HEADERS.to_vec())
+        };
+        let headers: csv::StringRecord = headers
+            .iter()
+            .map(|header| {
+                self.header_aliases
+                    .get(header)
+                    .map_or(header, String::as_str)
+            })
+            .collect();
+        let limit = self.rows_left();
+        let records = reader.records().take(limit);
+        let records: Box<dyn Iterator<Item = csv::Result<csv::StringRecord>>> =
+            if self.reverse_order {
+                Box::new(records.collect::<Vec<_>>().into_iter().rev())
+            } else {
+                Box::new(records)
+            };
+        for record in records {
+            self.rows_read += 1;
+            if let Some(sampling) = self.sampling {
+                if !sampling.picks(self.rows_read) {
+                    continue;
+                }
+            }
+            // Rows without any values, e.g. `,,,` padding at the end of a file,
+            // aren't malformed data worth a warning
+            if let Ok(record) = &record {
+                if record.iter().all(|field| field.trim().is_empty()) {
+                    continue;
+                }
+            }
+            let row = record.and_then(|record| {
+                let line = record.position().map(|position| position.line());
+                record
+                    .deserialize::<Row>(Some(&headers))
+                    .map(|row| (row, line))
+            });
+            match row {
+                Ok((row, line)) => self.apply_row(row, line),
+                // A failing input stream won't recover, unlike a malformed row
+                Err(e) if e.is_io_error() => return Err(e.into()),
+                Err(e) => {
+                    let line = e.position().map(|position| position.line());
+                    self.warn(line, WarningKind::InvalidRow(e.to_string()));
+                }
+            }
+        }
+        debug_assert!(
+            self.inconsistent_totals().is_empty(),
+            "Total funds don't match applied transactions for clients {:?}",
+            self.inconsistent_totals()
+        );
+        Ok(())
+    }
+
+    // Applies rows the same way CSV rows are applied, for embedding the engine
+    // without going through CSV; rejected rows are recorded as warnings
+    // without line numbers
+    pub fn apply_rows<I: IntoIterator<Item = Row>>(&mut self, rows: I) {
+        for row in rows {
+            self.apply_row(row, None);
+        }
+    }
+
+    // Converts a row into an operation and applies it
+    fn apply_row(&mut self, row: Row, line: Option<u64>) {
+        let client = match self.resolve_client(&row) {
+            Ok(client) => client,
+            Err(kind) => return self.warn(line, kind),
+        };
+        if let Some(filter) = &self.client_filter {
+            if !filter.contains(&client) {
+                return;
+            }
+        }
+        let amount = row.amount.unwrap_or_else(|| 0.into());
+        if self.precision_warnings && amount.normalize().scale() > DECIMAL_PLACES {
+            let scale = amount.normalize().scale();
+            self.warn(line, WarningKind::ExcessivePrecision { tx: row.tx, scale });
+        }
+        let amount = self.round_input(amount);
+        let amount = match to_amount(amount) {
+            Ok(amount) => amount,
+            Err(e) => return self.warn(line, WarningKind::InvalidAmount(e)),
+        };
+        let op = match row.op {
+            OperationType::Transaction if self.signed_amounts => {
+                Some(Operation::from_signed_amount(row.tx, client, amount))
+            }
+            OperationType::Transfer => match row.target {
+                Some(target) => Some(Operation::Transfer {
+                    tx: row.tx,
+                    client,
+                    target,
+                    amount,
+                }),
+                None => return self.warn(line, WarningKind::MissingTarget(row.tx)),
+            },
+            op => Operation::from_row(op, row.tx, client, amount),
+        };
+        match op {
+            Some(op) => {
+                if self.apply_at(op, line, row.note.as_deref()) {
+                    if let Some(timestamp) = row.timestamp {
+                        self.add_volume(timestamp, &op);
+                    }
+                }
+            }
+            None => self.warn(
+                line,
+                WarningKind::UnknownOperation(row.op.name().to_string()),
+            ),
+        }
+    }
+
+    // Adds an applied deposit or withdrawal to the volume of its time bucket.
+    // Volumes sum up amounts of all clients, so they saturate rather than overflow.
+    fn add_volume(&mut self, timestamp: u64, op: &Operation) {
+        let bucket_size = match self.volume_buckets {
+            Some(bucket_size) => bucket_size.seconds(),
+            None => return,
+        };
+        let bucket = timestamp - timestamp % bucket_size;
+        let volume = self.volumes.entry(bucket).or_default();
+        match *op {
+            Operation::Deposit { amount, .. } => {
+                volume.deposits = saturating_add(volume.deposits, amount)
+            }
+            Operation::Withdrawal { amount, .. } => {
+                volume.withdrawals = saturating_add(volume.withdrawals, amount)
+            }
+            _ => (),
+        }
+    }
+
+    // Recomputes total funds of each client independently from the applied
+    // transactions: deposits and withdrawals are stored with their signed
+    // amounts, while charged-back parts and voided amounts, as well as clamped
+    // dust, are gone for good, and adjustments change the totals directly.
+    // Returns clients for which the result doesn't match available + held.
+    // Nothing can be recomputed if transactions aren't stored, or only some are.
+    fn inconsistent_totals(&self) -> Vec<ClientId> {
+        if self.disputes_disabled || self.streaming {
+            return Vec::new();
+        }
+        let expected = self.expected_totals();
+        self.clients
+            .iter()
+            .filter(|(id, info)| {
+                expected.get(id).copied().unwrap_or_else(|| Some(zero())) != Some(info.total())
+            })
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    // Total funds of each client as recomputed by `inconsistent_totals`, or
+    // None for a client whose total doesn't fit in the amount type
+    fn expected_totals(&self) -> HashMap<ClientId, Option<Amount>> {
+        let mut amounts: HashMap<ClientId, Vec<Amount>> = HashMap::default();
+        for (client, seeded) in &self.seeded {
+            amounts.entry(*client).or_default().push(*seeded);
+        }
+        for (tx, tx_entry) in &self.transactions {
+            let client_amounts = amounts.entry(tx_entry.client).or_default();
+            if self.charged_back.contains(tx) {
+                client_amounts.push(tx_entry.amount);
+                client_amounts.push(zero() - self.dispute_amount(*tx, tx_entry));
+            } else if !self.voided.contains(tx) {
+                client_amounts.push(tx_entry.amount);
+            }
+        }
+        for dust in &self.clamped_dust {
+            amounts
+                .entry(dust.client)
+                .or_default()
+                .push(zero() - dust.amount);
+        }
+        for adjustment in self.adjustments.values() {
+            amounts
+                .entry(adjustment.client)
+                .or_default()
+                .push(adjustment.amount);
+        }
+        for (tx, source) in &self.transfer_sources {
+            amounts
+                .entry(*source)
+                .or_default()
+                .push(zero() - self.transactions[tx].amount);
+        }
+        amounts
+            .into_iter()
+            .map(|(client, amounts)| (client, checked_sum(amounts)))
+            .collect()
+    }
+
+    // Rows keyed by an external id get a sequential internal client id assigned
+    // on first sight; other rows must carry the client id directly
+    fn resolve_client(&mut self, row: &Row) -> Result<ClientId, WarningKind> {
+        let external_id = match row.external_id.as_deref() {
+            Some(external_id) if !external_id.is_empty() => external_id,
+            _ => return row.client.ok_or(WarningKind::MissingClient(row.tx)),
+        };
+        if let Some(client) = self.external_ids.get(external_id) {
+            return Ok(*client);
+        }
+        match ClientId::try_from(self.external_ids.len() + 1) {
+            Ok(client) => {
+                self.external_ids.insert(external_id.to_string(), client);
+                Ok(client)
+            }
+            Err(_) => Err(WarningKind::ExternalIdsExhausted(external_id.to_string())),
+        }
+    }
+
+    fn round_input(&self, amount: Decimal) -> Decimal {
+        match self.input_rounding {
+            Some(strategy) => amount.round_dp_with_strategy(DECIMAL_PLACES, strategy),
+            None if self.precision_warnings => amount.round_dp(DECIMAL_PLACES),
+            None => amount,
+        }
+    }
+
+    pub fn from_csv(path: &str) -> Result<Self, EngineError> {
+        let mut engine = Self::new();
+        engine.apply_csv_path(path)?;
+        Ok(engine)
+    }
+
+    // Applies all rows from the CSV file on top of the current state
+    // Files with the `.zst` extension are decompressed if the `zstd` feature is enabled,
+    // and ones with the `.gz` extension if the `gzip` feature is
+    pub fn apply_csv_path(&mut self, path: &str) -> Result<(), EngineError> {
+        #[cfg(feature = "zstd")]
+        if path.ends_with(".zst") {
+            return self.apply_zstd_csv_path(path);
+        }
+        #[cfg(feature = "gzip")]
+        if path.ends_with(".gz") {
+            return self.apply_gzip_csv(File::open(path)?);
+        }
+        self.apply_csv(File::open(path)?)
+    }
+
+    // Applies all rows from a CSV stream, e.g. stdin, read the same way as
+    // files are
+    pub fn apply_csv<R: io::Read>(&mut self, input: R) -> Result<(), EngineError> {
+        self.apply_csv_reader(self.input_reader_builder().from_reader(input))
+    }
+
+    pub fn from_ndjson_reader<R: io::Read>(input: R) -> Result<Self, EngineError> {
+        let mut engine = Self::new();
+        engine.apply_ndjson(input)?;
+        Ok(engine)
+    }
+
+    // Applies newline-delimited JSON input, one object with the same fields
+    // as CSV columns per line, e.g. {"type":"deposit","client":1,"tx":1,"amount":"1.5"}.
+    // Malformed lines are skipped with a warning, like malformed CSV rows.
+    pub fn apply_ndjson<R: io::Read>(&mut self, input: R) -> Result<(), EngineError> {
+        use io::BufRead;
+
+        self.input_txs.clear();
+        let input = match self.input_buffer_size {
+            Some(bytes) => io::BufReader::with_capacity(bytes, input),
+            None => io::BufReader::new(input),
+        };
+        let limit = self.rows_left();
+        for (index, line) in input.lines().enumerate().take(limit) {
+            let line = line?;
+            let number = Some(index as u64 + 1);
+            self.rows_read += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Self::parse_ndjson_row(&line) {
+                Ok(row) => self.apply_row(row, number),
+                Err(e) => self.warn(number, WarningKind::InvalidRow(e.to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_ndjson_row(line: &str) -> serde_json::Result<Row> {
+        let mut value: serde_json::Value = serde_json::from_str(line)?;
+        // Amounts are parsed from strings, like CSV cells, so that they're
+        // exact; JSON numbers are accepted as written
+        if let Some(amount) = value.get_mut("amount") {
+            if let serde_json::Value::Number(number) = amount {
+                *amount = serde_json::Value::String(number.to_string());
+            }
+        }
+        serde_json::from_value(value)
+    }
+
+    #[cfg(feature = "zstd")]
+    pub fn apply_zstd_csv_path(&mut self, path: &str) -> Result<(), EngineError> {
+        self.apply_zstd_csv(File::open(path)?)
+    }
+
+    // Applies rows from a zstd-compressed CSV stream; a corrupt stream is
+    // reported as an I/O error
+    #[cfg(feature = "zstd")]
+    pub fn apply_zstd_csv<R: io::Read>(&mut self, input: R) -> Result<(), EngineError> {
+        let decoder = zstd::Decoder::new(input)?;
+        self.apply_csv_reader(self.input_reader_builder().from_reader(decoder))
+    }
+
+    // Applies rows from a gzip-compressed CSV stream; a corrupt stream is
+    // reported as an I/O error
+    #[cfg(feature = "gzip")]
+    pub fn apply_gzip_csv<R: io::Read>(&mut self, input: R) -> Result<(), EngineError> {
+        let decoder = flate2::read::GzDecoder::new(input);
+        self.apply_csv_reader(self.input_reader_builder().from_reader(decoder))
+    }
+
+    fn csv_reader_builder() -> ReaderBuilder {
+        let mut builder = ReaderBuilder::new();
+        builder.trim(Trim::All).flexible(true);
+        builder
+    }
+
+    fn input_reader_builder(&self) -> ReaderBuilder {
+        let mut builder = Self::csv_reader_builder();
+        builder.has_headers(!self.headerless);
+        if let Some(bytes) = self.input_buffer_size {
+            builder.buffer_capacity(bytes);
+        }
+        builder
+    }
+
+    // The writer is flushed before returning, so the output is complete once
+    // this succeeds, even if the writer is never dropped. Rows are buffered by
+    // the CSV writer in between, so wrapping the writer in a `BufWriter` gains
+    // nothing, see the `output` benchmark.
+    pub fn to_csv_writer<W: Write>(&self, writer: W) -> io::Result<()> {
+        self.write_clients(writer, |_| true)
+    }
+
+    pub fn to_csv(&self) -> io::Result<()> {
+        self.to_csv_writer(io::stdout().lock())
+    }
+
+    // Writes balances as a JSON array of objects sorted by client id, with
+    // amounts as strings so that no precision is lost to floats
+    pub fn to_json(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut balances: Vec<JsonBalance> = self
+            .clients
+            .iter()
+            .map(|(client, info)| JsonBalance {
+                client: self.output_id(client),
+                available: round_output(info.available),
+                held: round_output(info.held),
+                total: round_output(info.total()),
+                locked: info.locked,
+            })
+            .collect();
+        balances.sort_by_key(|balance| balance.client);
+        serde_json::to_writer(&mut writer, &balances)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+
+    // Writes the client id behind each pseudonym of anonymized output as
+    // `client,pseudonym` rows sorted by client id
+    pub fn write_anonymization_map<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::WriterBuilder::new().from_writer(writer);
+        writer.write_record(["client", "pseudonym"])?;
+        let mut clients: Vec<ClientId> = self.clients.keys().collect();
+        clients.sort_unstable();
+        for client in clients {
+            writer.write_record([client.to_string(), self.output_id(client).to_string()])?;
+        }
+        writer.flush()
+    }
+
+    // Writes balances partitioned by `client % shards` into `shard-<n>.csv` files
+    // in the given directory, one file per shard, each with its own header.
+    // Anonymized output is partitioned by the pseudonyms.
+    pub fn to_sharded_csv(&self, dir: &Path, shards: u16) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        for shard in 0..shards {
+            let file = File::create(dir.join(format!("shard-{}.csv", shard)))?;
+            self.write_clients(file, |id| self.output_id(id) % shards == shard)?;
+        }
+        Ok(())
+    }
+
+    // Stored transactions ordered by id, so that everything derived from them
+    // is stable across runs
+    fn sorted_transactions(&self) -> Vec<(TxId, &Transaction)> {
+        let mut transactions: Vec<(TxId, &Transaction)> = self
+            .transactions
+            .iter()
+            .map(|(tx, tx_entry)| (*tx, tx_entry))
+            .collect();
+        transactions.sort_unstable_by_key(|(tx, _)| *tx);
+        transactions
+    }
+
+    // Writes stored deposits, withdrawals and transfers, as well as adjustments
+    // with their signed amounts and notes, sorted by transaction id, in the
+    // input format extended with each transaction's dispute state. Transfers
+    // are written from the source client to the target.
+    pub fn write_journal<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::WriterBuilder::new().from_writer(writer);
+        writer.write_record(["type", "client", "tx", "amount", "state", "note", "target"])?;
+        let mut records: Vec<(TxId, [String; 7])> = self
+            .sorted_transactions()
+            .into_iter()
+            .map(|(tx, tx_entry)| {
+                let (op, state) = self.journal_entry(tx, tx_entry);
+                let (client, target) = match self.transfer_sources.get(&tx) {
+                    Some(source) => (*source, tx_entry.client.to_string()),
+                    None => (tx_entry.client, String::new()),
+                };
+                let record = [
+                    op.to_string(),
+                    client.to_string(),
+                    tx.to_string(),
+                    tx_entry.amount.abs().to_string(),
+                    state.to_string(),
+                    String::new(),
+                    target,
+                ];
+                (tx, record)
+            })
+            .collect();
+        for (tx, adjustment) in &self.adjustments {
+            let record = [
+                OperationType::Adjustment.name().to_string(),
+                adjustment.client.to_string(),
+                tx.to_string(),
+                adjustment.amount.to_string(),
+                String::new(),
+                adjustment.note.clone(),
+                String::new(),
+            ];
+            records.push((*tx, record));
+        }
+        records.sort_by_key(|(tx, _)| *tx);
+        for (_, record) in records {
+            writer.write_record(&record)?;
+        }
+        writer.flush()
+    }
+
+    // Type and state of a stored transaction, as in the journal; the state is
+    // empty unless the transaction is disputed, charged back, voided or refunded
+    fn journal_entry(&self, tx: TxId, tx_entry: &Transaction) -> (&'static str, &'static str) {
+        let op = if self.transfer_sources.contains_key(&tx) {
+            "transfer"
+        } else if tx_entry.amount.is_sign_negative() {
+            "withdrawal"
+        } else {
+            "deposit"
+        };
+        let state = if self.charged_back.contains(&tx) {
+            "charged_back"
+        } else if self.refunded.contains(&tx) {
+            "refunded"
+        } else if self.voided.contains(&tx) {
+            "voided"
+        } else if self.disputed.contains(&tx) {
+            "disputed"
+        } else {
+            ""
+        };
+        (op, state)
+    }
+
+    // Whole state as a JSON value, for embedding in other documents: clients
+    // with their balances, stored transactions as in the journal and open
+    // disputes with the amounts they hold, each sorted by id
+    pub fn to_value(&self) -> serde_json::Value {
+        let mut clients: Vec<(ClientId, &ClientInfo)> = self.clients.iter().collect();
+        clients.sort_by_key(|(id, _)| *id);
+        let clients: Vec<serde_json::Value> = clients
+            .into_iter()
+            .map(|(id, info)| {
+                serde_json::json!({
+                    "client": id,
+                    "available": info.available,
+                    "held": info.held,
+                    "total": info.total(),
+                    "locked": info.locked,
+                    "lock_reason": info.lock_reason.map(|reason| reason.to_string()),
+                })
+            })
+            .collect();
+        let transactions = self.sorted_transactions();
+        let disputes: Vec<serde_json::Value> = transactions
+            .iter()
+            .filter(|(tx, _)| self.disputed.contains(tx))
+            .map(|(tx, tx_entry)| {
+                serde_json::json!({
+                    "tx": tx,
+                    "client": tx_entry.client,
+                    "amount": self.dispute_amount(*tx, tx_entry),
+                })
+            })
+            .collect();
+        let transactions: Vec<serde_json::Value> = transactions
+            .into_iter()
+            .map(|(tx, tx_entry)| {
+                let (op, state) = self.journal_entry(tx, tx_entry);
+                serde_json::json!({
+                    "tx": tx,
+                    "type": op,
+                    "client": tx_entry.client,
+                    "amount": tx_entry.amount.abs(),
+                    "state": state,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "clients": clients,
+            "transactions": transactions,
+            "disputes": disputes,
+        })
+    }
+
+    // Balance timeline of the client traced with `with_client_trace`
+    pub fn trace(&self) -> &[TracePoint] {
+        &self.trace
+    }
+
+    // Writes the balance timeline of the traced client
+    pub fn write_trace<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::WriterBuilder::new().from_writer(writer);
+        writer.write_record(["type", "tx", "available", "held"])?;
+        for point in &self.trace {
+            writer.write_record([
+                point.op.as_str(),
+                &point.tx.to_string(),
+                &point.available.to_string(),
+                &point.held.to_string(),
+            ])?;
+        }
+        writer.flush()
+    }
+
+    // Writes all disputes tracked with `with_dispute_tracking` in the order they
+    // were applied; a transaction disputed again after a resolve has several rows
+    pub fn write_disputes_report<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::WriterBuilder::new().from_writer(writer);
+        writer.write_record(["tx", "client", "amount", "outcome"])?;
+        for record in &self.dispute_log {
+            writer.write_record([
+                &record.tx.to_string(),
+                &record.client.to_string(),
+                &record.amount.to_string(),
+                record.outcome.name(),
+            ])?;
+        }
+        writer.flush()
+    }
+
+    // Writes deposit and withdrawal volumes per time bucket, ordered by the
+    // bucket's start in seconds since the epoch
+    pub fn write_volumes<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = csv::WriterBuilder::new().from_writer(writer);
+        writer.write_record(["bucket", "deposits", "withdrawals"])?;
+        for (bucket, volume) in &self.volumes {
+            writer.write_record([
+                bucket.to_string(),
+                volume.deposits.to_string(),
+                volume.withdrawals.to_string(),
+            ])?;
+        }
+        writer.flush()
+    }
+
+    // Continues from a prior run: loads balances in the output format, and
+    // stored transactions along with their dispute state in the journal format
+    // written by `write_journal`, so that transactions from the previous period
+    // can still be disputed. Withdrawals are drawn down from the deposits again,
+    // oldest transaction ids first. Invalid rows are skipped with a warning.
+    pub fn resume<B: io::Read, J: io::Read>(
+        &mut self,
+        mut balances: csv::Reader<B>,
+        mut journal: csv::Reader<J>,
+    ) -> Result<(), EngineError> {
+        for record in balances.deserialize::<BalanceRow>() {
+            let row = match record {
+                Ok(row) => row,
+                Err(e) if e.is_io_error() => return Err(e.into()),
+                Err(e) => {
+                    let line = e.position().map(|position| position.line());
+                    self.warn(line, WarningKind::InvalidRow(e.to_string()));
+                    continue;
+                }
+            };
+            let (available, held) = match (to_amount(row.available), to_amount(row.held)) {
+                (Ok(available), Ok(held)) => (available, held),
+                (Err(e), _) | (_, Err(e)) => {
+                    self.warn(None, WarningKind::InvalidAmount(e));
+                    continue;
+                }
+            };
+            if let Some(external_id) = row.external_id.filter(|id| !id.is_empty()) {
+                self.external_ids.insert(external_id, row.client);
+            }
+            let lock_reason = match row.lock_reason.as_deref() {
+                None | Some("") => None,
+                Some(reason) => match LockReason::from_str(reason) {
+                    Ok(reason) => Some(reason),
+                    Err(e) => {
+                        self.warn(None, WarningKind::InvalidRow(e));
+                        continue;
+                    }
+                },
+            };
+            let mut info = ClientInfo::with_held_locked(available, held, row.locked);
+            info.lock_reason = lock_reason;
+            self.clients.insert(row.client, info);
+            self.touch(row.client, None);
+        }
+
+        // Withdrawals and transfers to draw from the deposits, the latter flagged
+        let mut to_draw = Vec::new();
+        for record in journal.deserialize::<JournalRow>() {
+            let row = match record {
+                Ok(row) => row,
+                Err(e) if e.is_io_error() => return Err(e.into()),
+                Err(e) => {
+                    let line = e.position().map(|position| position.line());
+                    self.warn(line, WarningKind::InvalidRow(e.to_string()));
+                    continue;
+                }
+            };
+            let mut amount = match to_amount(row.amount) {
+                Ok(amount) => amount,
+                Err(e) => {
+                    self.warn(None, WarningKind::InvalidAmount(e));
+                    continue;
+                }
+            };
+            let (tx, mut client) = (row.tx, row.client);
+            match row.op {
+                OperationType::Deposit => (),
+                OperationType::Withdrawal => amount.set_sign_negative(true),
+                OperationType::Adjustment => {
+                    let note = row.note.unwrap_or_default();
+                    self.adjustments.insert(
+                        tx,
+                        Adjustment {
+                            client,
+                            amount,
+                            note,
+                        },
+                    );
+                    self.touch(client, Some(tx));
+                    continue;
+                }
+                // Stored as the target's deposit, like when applying the transfer,
+                // while the source's side is drawn from its deposits below
+                OperationType::Transfer => match row.target {
+                    Some(target) => {
+                        self.transfer_sources.insert(tx, client);
+                        to_draw.push((tx, client, amount, true));
+                        self.touch(client, None);
+                        client = target;
+                    }
+                    None => {
+                        self.warn(None, WarningKind::MissingTarget(tx));
+                        continue;
+                    }
+                },
+                op => {
+                    self.warn(None, WarningKind::UnknownOperation(op.name().to_string()));
+                    continue;
+                }
+            }
+            self.transactions.insert(tx, Transaction { client, amount });
+            match row.state.as_str() {
+                "disputed" => {
+                    self.disputed.insert(tx);
+                }
+                "charged_back" => {
+                    self.charged_back.insert(tx);
+                }
+                "voided" => {
+                    self.voided.insert(tx);
+                }
+                "refunded" => {
+                    self.voided.insert(tx);
+                    self.refunded.insert(tx);
+                }
+                _ => (),
+            }
+            // Without more detail in the journal, disputes are assumed to hold
+            // the whole amount
+            if self.disputed.contains(&tx) || self.charged_back.contains(&tx) {
+                self.dispute_amounts.insert(tx, amount);
+            }
+            let settled = self.charged_back.contains(&tx) || self.voided.contains(&tx);
+            if amount.is_sign_negative() {
+                if !settled && !self.disputed.contains(&tx) {
+                    to_draw.push((tx, client, amount.abs(), false));
+                }
+            } else if !settled {
+                self.remaining.entry(client).or_default().insert(tx, amount);
+            }
+            self.touch(client, Some(tx));
+        }
+        to_draw.sort_unstable_by_key(|(tx, _, _, _)| *tx);
+        for (tx, client, amount, transfer) in to_draw {
+            self.draw_down(client, tx, amount);
+            if transfer {
+                self.draws.remove(&tx);
+            }
+        }
+        // Loaded balances are authoritative, so whatever the journal doesn't
+        // explain is kept as seeded funds for `inconsistent_totals`
+        let expected = self.expected_totals();
+        for (id, info) in self.clients.iter() {
+            // A client whose journal doesn't even add up stays inconsistent
+            let seeded = match expected.get(&id).copied().unwrap_or_else(|| Some(zero())) {
+                Some(expected) => checked_sub(info.total(), expected),
+                None => None,
+            };
+            if let Some(seeded) = seeded.filter(|seeded| *seeded != zero()) {
+                *self.seeded.entry(id).or_insert_with(zero) += seeded;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn resume_from_paths(&mut self, balances: &str, journal: &str) -> Result<(), EngineError> {
+        self.resume(
+            Self::csv_reader_builder().from_path(balances)?,
+            Self::csv_reader_builder().from_path(journal)?,
+        )
+    }
+
+    // Columns selected with `with_output_fields`, or the default ones: external
+    // ids are only printed if the input used them at all, and other optional
+    // columns only if they're enabled
+    fn output_fields(&self, with_external_ids: bool) -> Vec<OutputField> {
+        if let Some(fields) = &self.output_fields {
+            return fields.clone();
+        }
+        let mut fields = vec![OutputField::Client];
+        if with_external_ids {
+            fields.push(OutputField::ExternalId);
+        }
+        fields.extend([
+            OutputField::Available,
+            OutputField::Held,
+            OutputField::Total,
+            OutputField::Locked,
+        ]);
+        if self.lock_reason_column {
+            fields.push(OutputField::LockReason);
+        }
+        #[cfg(feature = "row-hash")]
+        if self.row_hash_key.is_some() {
+            fields.push(OutputField::Hash);
+        }
+        fields
+    }
+
+    // Uses a CSV writer, so that fields are quoted whenever needed, e.g.
+    // an external id containing a comma. Clients are sorted by id, so that
+    // the same state always produces the same output.
+    fn write_clients<W: Write, F: Fn(ClientId) -> bool>(
+        &self,
+        mut writer: W,
+        filter: F,
+    ) -> io::Result<()> {
+        let empty = !self.clients.keys().any(&filter);
+        let with_header = self.header_policy == HeaderPolicy::Always || !empty;
+        if self.fixed_point_output && with_header {
+            writeln!(writer, "# scale={}", DECIMAL_PLACES)?;
+        }
+        let mut writer = csv::WriterBuilder::new().from_writer(writer);
+        let external_ids: HashMap<ClientId, &str> = match self.anonymize_seed {
+            Some(_) => HashMap::default(),
+            None => self
+                .external_ids
+                .iter()
+                .map(|(external_id, id)| (*id, external_id.as_str()))
+                .collect(),
+        };
+        let fields = self.output_fields(!external_ids.is_empty());
+        if with_header {
+            writer.write_record(fields.iter().map(|field| field.name()))?;
+        }
+        let amount = |amount: Amount| {
+            if self.fixed_point_output {
+                to_minor_units(amount).to_string()
+            } else {
+                round_output(amount).to_string()
+            }
+        };
+        let mut clients: Vec<(ClientId, &ClientInfo)> =
+            self.clients.iter().filter(|(id, _)| filter(*id)).collect();
+        clients.sort_by_key(|(id, _)| self.output_id(*id));
+        for (id, info) in clients {
+            let record = fields.iter().map(|field| match field {
+                OutputField::Client => self.output_id(id).to_string(),
+                OutputField::ExternalId => external_ids.get(&id).unwrap_or(&"").to_string(),
+                OutputField::Available => amount(info.available),
+                OutputField::Held => amount(info.held),
+                OutputField::Total => amount(info.total()),
+                OutputField::Locked => info.locked.to_string(),
+                OutputField::LockReason => {
+                    info.lock_reason.map_or_else(String::new, |r| r.to_string())
+                }
+                #[cfg(feature = "row-hash")]
+                OutputField::Hash => self
+                    .row_hash_key
+                    .as_ref()
+                    .map_or_else(String::new, |key| row_hash(key, id, info)),
+            });
+            writer.write_record(record)?;
+        }
+        writer.flush()
+    }
+
+    // Sum of total funds of all clients, saturating like `ClientInfo::total`
+    pub fn grand_total(&self) -> Amount {
+        self.clients.values().fold(0.into(), |sum: Amount, info| {
+            saturating_add(sum, info.total())
+        })
+    }
+
+    // Checks the grand total against the expected one, e.g. zero for a closed
+    // system where every withdrawal corresponds to a deposit elsewhere.
+    // Returns the actual grand total on mismatch.
+    pub fn check_balanced(&self, expected: Amount) -> Result<(), Amount> {
+        let total = self.grand_total();
+        if total == expected {
+            Ok(())
+        } else {
+            Err(total)
+        }
+    }
+
+    // Post-processing pass which zeroes out available and held balances whose
+    // absolute value is below the threshold, e.g. `-0.0001` left over from
+    // disputing withdrawals. Returns the clamped balances, which are also
+    // recorded for audit.
+    pub fn clamp_dust(&mut self, threshold: Amount) -> Vec<ClampedDust> {
+        let mut clamped = Vec::new();
+        for (id, info) in self.clients.iter_mut() {
+            for (balance, amount) in [
+                (Balance::Available, &mut info.available),
+                (Balance::Held, &mut info.held),
+            ] {
+                if *amount != zero() && amount.abs() < threshold {
+                    clamped.push(ClampedDust {
+                        client: id,
+                        balance,
+                        amount: *amount,
+                    });
+                    *amount = 0.into();
+                }
+            }
+        }
+        if !clamped.is_empty() {
+            self.seq += 1;
+            for dust in &clamped {
+                self.touch(dust.client, None);
+            }
+        }
+        self.clamped_dust.extend_from_slice(&clamped);
+        clamped
+    }
+
+    // Unlocks a locked client, validated by `valid`
+    fn unlock(&mut self, client: ClientId) -> Result<(), EngineError> {
+        let info = self
+            .clients
+            .get_mut(&client)
+            .ok_or(WarningKind::ClientNotFound(client))?;
+        info.locked = false;
+        info.lock_reason = None;
+        Ok(())
+    }
+
+    // Locks the client manually, e.g. on an administrator's request
+    pub fn freeze(&mut self, client: ClientId) -> Result<(), WarningKind> {
+        let info = self
+            .clients
+            .get_mut(&client)
+            .ok_or(WarningKind::ClientNotFound(client))?;
+        info.locked = true;
+        info.lock_reason = Some(LockReason::Admin);
+        self.seq += 1;
+        self.touch(client, None);
+        Ok(())
+    }
+
+    // Returns all balances clamped by `clamp_dust` so far
+    pub fn clamped_dust(&self) -> &[ClampedDust] {
+        &self.clamped_dust
+    }
+
+    // Enables tracking which clients and transactions changed at which sequence
+    // number, so that `snapshot_delta_since` only includes what actually changed.
+    // Tracking costs extra memory per client and transaction, so it's off by default.
+    pub fn with_change_tracking(mut self) -> Self {
+        self.track_changes = true;
+        self
+    }
+
+    // Sequence number of the last change, incremented on each applied operation
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // Full snapshot of clients, transactions and their dispute state
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            seq: self.seq,
+            clients: self
+                .clients
+                .iter()
+                .map(|(id, info)| (id, info.clone()))
+                .collect(),
+            transactions: self
+                .sorted_transactions()
+                .into_iter()
+                .map(|(tx, tx_entry)| (tx, self.transaction_state(tx, tx_entry)))
+                .collect(),
+            adjustments: self
+                .adjustments
+                .iter()
+                .map(|(tx, adjustment)| (*tx, adjustment.clone()))
+                .collect(),
+        }
+        .sorted()
+    }
+
+    // Snapshot of clients and transactions changed after `prev_seq`. Without change
+    // tracking enabled, everything is considered changed.
+    pub fn snapshot_delta_since(&self, prev_seq: u64) -> Snapshot {
+        if !self.track_changes {
+            return self.snapshot();
+        }
+        fn changed<K: Hash + Eq>(seqs: &HashMap<K, u64>, key: &K, prev_seq: u64) -> bool {
+            seqs.get(key).is_some_and(|seq| *seq > prev_seq)
+        }
+        Snapshot {
+            seq: self.seq,
+            clients: self
+                .clients
+                .iter()
+                .filter(|(id, _)| changed(&self.client_seqs, id, prev_seq))
+                .map(|(id, info)| (id, info.clone()))
+                .collect(),
+            transactions: self
+                .sorted_transactions()
+                .into_iter()
+                .filter(|(tx, _)| changed(&self.tx_seqs, tx, prev_seq))
+                .map(|(tx, tx_entry)| (tx, self.transaction_state(tx, tx_entry)))
+                .collect(),
+            adjustments: self
+                .adjustments
+                .iter()
+                .filter(|(tx, _)| changed(&self.tx_seqs, tx, prev_seq))
+                .map(|(tx, adjustment)| (*tx, adjustment.clone()))
+                .collect(),
+        }
+        .sorted()
+    }
+
+    // Applies a full snapshot or a delta on top of the current state
+    pub fn apply_delta(&mut self, delta: &Snapshot) {
+        self.seq = self.seq.max(delta.seq);
+        for (id, info) in &delta.clients {
+            self.clients.insert(*id, info.clone());
+            self.touch(*id, None);
+        }
+        for (tx, state) in &delta.transactions {
+            self.transactions.insert(*tx, state.transaction);
+            if state.disputed {
+                self.disputed.insert(*tx);
+            } else {
+                self.disputed.remove(tx);
+            }
+            if state.charged_back {
+                self.charged_back.insert(*tx);
+            }
+            if state.voided {
+                self.voided.insert(*tx);
+            }
+            if state.refunded {
+                self.refunded.insert(*tx);
+            }
+            if let Some(source) = state.source {
+                self.transfer_sources.insert(*tx, source);
+            }
+            let deposits = self.remaining.entry(state.transaction.client).or_default();
+            match state.remaining {
+                Some(remaining) => deposits.insert(*tx, remaining),
+                None => deposits.remove(tx),
+            };
+            match state.dispute_amount {
+                Some(amount) => self.dispute_amounts.insert(*tx, amount),
+                None => self.dispute_amounts.remove(tx),
+            };
+            if state.draws.is_empty() {
+                self.draws.remove(tx);
+            } else {
+                self.draws.insert(*tx, state.draws.clone());
+            }
+            self.touch(state.transaction.client, Some(*tx));
+        }
+        for (tx, adjustment) in &delta.adjustments {
+            self.adjustments.insert(*tx, adjustment.clone());
+            self.touch(adjustment.client, Some(*tx));
+        }
+    }
+
+    fn transaction_state(&self, tx: TxId, tx_entry: &Transaction) -> TransactionState {
+        TransactionState {
+            transaction: *tx_entry,
+            disputed: self.disputed.contains(&tx),
+            charged_back: self.charged_back.contains(&tx),
+            voided: self.voided.contains(&tx),
+            refunded: self.refunded.contains(&tx),
+            source: self.transfer_sources.get(&tx).copied(),
+            remaining: self
+                .remaining
+                .get(&tx_entry.client)
+                .and_then(|deposits| deposits.get(&tx))
+                .copied(),
+            dispute_amount: self.dispute_amounts.get(&tx).copied(),
+            draws: self.draws.get(&tx).cloned().unwrap_or_default(),
+        }
+    }
+
+    // Hash of the full snapshot, equal for engines in equal states. The sequence
+    // number is left out: it also counts operations rejected after validation,
+    // so an engine replaying only the applied ones reaches the same state at
+    // a lower sequence number.
+    pub fn state_hash(&self) -> u64 {
+        let snapshot = Snapshot {
+            seq: 0,
+            ..self.snapshot()
+        };
+        let bytes = serde_json::to_vec(&snapshot).expect("snapshots are always serializable");
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&bytes);
+        hasher.finish()
+    }
+
+    fn snapshot_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.snapshot()).expect("snapshots are always serializable")
+    }
+
+    // Self-test of the snapshot machinery: serializes a snapshot, reloads it
+    // into a fresh engine and checks that the reloaded engine serializes to
+    // the same bytes and has the same state hash
+    pub fn check_round_trip(&self) -> Result<(), String> {
+        let bytes = self.snapshot_bytes();
+        let snapshot: Snapshot = serde_json::from_slice(&bytes)
+            .map_err(|e| format!("Failed to reload snapshot: {}", e))?;
+        let mut reloaded = Self::new();
+        reloaded.apply_delta(&snapshot);
+        if reloaded.snapshot_bytes() != bytes {
+            return Err("Reloaded snapshot differs from the original".to_string());
+        }
+        let (hash, reloaded_hash) = (self.state_hash(), reloaded.state_hash());
+        if hash != reloaded_hash {
+            return Err(format!(
+                "State hash {:016x} of the reloaded engine doesn't match the original {:016x}",
+                reloaded_hash, hash
+            ));
+        }
+        Ok(())
+    }
+
+    // Checks that total funds of each client match the applied transactions,
+    // returning clients for which they don't, sorted by id
+    pub fn verify_integrity(&self) -> Result<(), Vec<ClientId>> {
+        let mut inconsistent = self.inconsistent_totals();
+        if inconsistent.is_empty() {
+            return Ok(());
+        }
+        inconsistent.sort_unstable();
+        Err(inconsistent)
+    }
+
+    // Returns whether the transaction is currently disputed, i.e. it was disputed
+    // and has been neither resolved nor charged back since
+    pub fn is_disputed(&self, tx: TxId) -> bool {
+        self.disputed.contains(&tx)
+    }
+
+    // Funds held by the client's disputes, or None for an unknown client
+    pub fn held(&self, client: ClientId) -> Option<Amount> {
+        self.clients.get(&client).map(|info| info.held)
+    }
+
+    /// Balances of a single client, or None for an unknown client
+    ///
+    /// ```
+    /// use transactions::{Amount, Operation, TransactionEngine};
+    ///
+    /// let mut engine = TransactionEngine::new();
+    /// engine.apply(Operation::Deposit {
+    ///     tx: 1,
+    ///     client: 1,
+    ///     amount: Amount::from(5),
+    /// });
+    /// assert_eq!(engine.client(1).unwrap().available(), Amount::from(5));
+    /// assert!(engine.client(2).is_none());
+    /// ```
+    pub fn client(&self, client: ClientId) -> Option<&ClientInfo> {
+        self.clients.get(&client)
+    }
+
+    // All known clients and their balances, keyed by client id
+    pub fn clients(&self) -> &ClientStore {
+        &self.clients
+    }
+}
+
+#[cfg(feature = "row-hash")]
+fn row_hash(key: &[u8], id: ClientId, info: &ClientInfo) -> String {
+    use hmac::{Hmac, Mac};
+    use std::fmt::Write;
+
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).unwrap();
+    mac.update(
+        format!(
+            "{},{},{},{},{}",
+            id,
+            info.available,
+            info.held,
+            info.total(),
+            info.locked
+        )
+        .as_bytes(),
+    );
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+// Single input row, as read from CSV
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Row {
+    #[serde(rename = "type")]
+    pub op: OperationType,
+    pub client: Option<ClientId>,
+    // Optional string key of the client, mapped to a sequential internal client id
+    pub external_id: Option<String>,
+    pub tx: TxId,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub amount: Option<Decimal>,
+    // Optional time of the transaction in seconds since the epoch
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    // Optional audit note of adjustments
+    #[serde(default)]
+    pub note: Option<String>,
+    // Client receiving a transfer
+    #[serde(default)]
+    pub target: Option<ClientId>,
+}
+
+// Applies rows to a new engine the same way CSV rows are applied; rejected
+// rows are recorded as warnings without line numbers
+impl std::iter::FromIterator<Row> for TransactionEngine {
+    fn from_iter<I: IntoIterator<Item = Row>>(rows: I) -> Self {
+        let mut engine = Self::new();
+        engine.apply_rows(rows);
+        engine
+    }
+}
+
+// Balances of a client in the JSON output
+#[derive(Debug, Serialize)]
+struct JsonBalance {
+    client: ClientId,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+// Balances row in the output format; `total` and `hash` columns are ignored
+#[derive(Debug, Deserialize)]
+struct BalanceRow {
+    client: ClientId,
+    external_id: Option<String>,
+    available: Decimal,
+    held: Decimal,
+    locked: bool,
+    lock_reason: Option<String>,
+}
+
+// Transaction row in the format written by `write_journal`
+#[derive(Debug, Deserialize)]
+struct JournalRow {
+    #[serde(rename = "type")]
+    op: OperationType,
+    client: ClientId,
+    tx: TxId,
+    amount: Decimal,
+    state: String,
+    // Only present for adjustments, and missing in journals of older versions
+    #[serde(default)]
+    note: Option<String>,
+    // Only present for transfers, like `note`
+    #[serde(default)]
+    target: Option<ClientId>,
+}
+
+// Empty and whitespace-only amount cells are treated as absent regardless
+// of the reader's trim settings, while present but unparseable amounts
+// reject the whole row
+fn deserialize_amount<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Decimal>, D::Error> {
+    let cell: Option<String> = Option::deserialize(deserializer)?;
+    match cell.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(amount) => Decimal::from_str(amount)
+            .map(Some)
+            .map_err(|e| de::Error::custom(format!("invalid amount {:?}: {}", amount, e))),
+    }
+}
+
+#[cfg(test)]
+fn test_clients(input: &str) -> HashMap<ClientId, ClientInfo> {
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let clients = engine.clients();
+    clients
+        .iter()
+        .map(|(id, info)| (id, info.clone()))
+        .collect()
+}
+
+#[test]
+fn test_deposit_duplicated() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,1,1.0
+deposit,2,1,1.0
+deposit,3,1,1.0
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 1.into());
+    assert!(!client.locked);
+}
+
+#[test]
+fn test_ndjson_deposit_duplicated() {
+    let input = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.0"}
+{"type":"deposit","client":1,"tx":1,"amount":"1.0"}
+{"type":"deposit","client":2,"tx":1,"amount":1.0}
+
+{"type":"deposit","client":3,"tx":1,"amount":1}
+{"type":"deposit","client":4,"tx":2,"amount":"1.0"
+{"type":"bogus","client":4,"tx":3}
+"#;
+    let engine = TransactionEngine::from_ndjson_reader(input.as_bytes()).unwrap();
+    let clients = engine.clients();
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 1.into());
+    assert!(!client.locked);
+    let warnings = engine.warnings();
+    let kinds: Vec<&str> = warnings.iter().map(|w| w.kind.name()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            "transaction_exists",
+            "transaction_exists",
+            "transaction_exists",
+            "invalid_row",
+            "invalid_row"
+        ]
+    );
+    assert_eq!(warnings[3].line, Some(6));
+    assert_eq!(engine.rows_read(), 7);
+}
+
+#[test]
+fn test_withdraw() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,7.0
+withdrawal,1,3,5.1
+withdrawal,1,4,5.5
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 5.into());
+    assert!(!client.locked);
+}
+
+#[test]
+fn test_dispute_unresolved() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+deposit,1,3,4.0
+deposit,1,4,1.0
+dispute,1,4,
+dispute,1,2,
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 9.into());
+    assert_eq!(client.held, 4.into());
+    assert!(!client.locked);
+}
+
+#[test]
+fn test_dispute_all_deposits() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+deposit,1,2,5
+dispute,1,1,
+dispute,1,2,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert!(engine.warnings().is_empty());
+    assert_eq!(engine.held(1), Some(10.into()));
+    assert_eq!(engine.held(2), None);
+    assert_eq!(engine.clients().get(&1).unwrap().available, 0.into());
+}
+
+#[test]
+fn test_dispute_resolve() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+deposit,1,3,4.0
+deposit,1,4,1.0
+dispute,1,4,
+dispute,1,2,
+resolve,1,4,
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 10.into());
+    assert_eq!(client.held, 3.into());
+    assert!(!client.locked);
+}
+
+#[test]
+fn test_dispute_chargeback() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+deposit,1,3,4.0
+deposit,1,4,1.0
+dispute,1,4,
+dispute,1,2,
+chargeback,1,4,
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 9.into());
+    assert_eq!(client.held, 3.into());
+    assert!(client.locked);
+}
+
+#[test]
+fn test_dispute_after_chargeback() {
+    let mut engine = TransactionEngine::new();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 5.into(),
+    });
+    engine.apply(Operation::Withdrawal {
+        tx: 2,
+        client: 1,
+        amount: 2.into(),
+    });
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    engine.apply(Operation::Chargeback { tx: 1, client: 1 });
+    let client = &engine.clients()[&1];
+    assert_eq!(client.available, 0.into());
+    assert_eq!(client.held, 0.into());
+    assert!(client.locked);
+    let before = engine.snapshot();
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    let after = engine.snapshot();
+    assert_eq!(after.clients, before.clients);
+    assert_eq!(after.transactions, before.transactions);
+    assert_eq!(engine.warnings()[0].kind, WarningKind::ClientLocked(1));
+
+    // Even with the lock lifted, the transaction stays final
+    let mut unlocked = before.clone();
+    unlocked.clients[0].1.locked = false;
+    let mut engine = TransactionEngine::new();
+    engine.apply_delta(&unlocked);
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    engine.apply(Operation::Resolve { tx: 1, client: 1 });
+    engine.apply(Operation::Chargeback { tx: 1, client: 1 });
+    let after = engine.snapshot();
+    assert_eq!(after.clients, unlocked.clients);
+    assert_eq!(after.transactions, unlocked.transactions);
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, vec![WarningKind::TransactionChargedBack(1); 3]);
+}
+
+#[test]
+fn test_unlock() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+deposit,1,2,3
+dispute,1,2,
+chargeback,1,2,
+deposit,1,3,1
+unlock,1,4,
+deposit,1,5,2
+unlock,1,6,
+unlock,2,7,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let client = &engine.clients()[&1];
+    assert_eq!(client.available, 7.into());
+    assert_eq!(client.held, 0.into());
+    assert!(!client.locked);
+    assert_eq!(client.lock_reason, None);
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::ClientLocked(1),
+            WarningKind::ClientNotLocked(1),
+            WarningKind::ClientNotFound(2),
+        ]
+    );
+    assert!(engine.clients().get(&2).is_none());
+}
+
+#[test]
+fn test_lock_reason() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+deposit,1,2,3
+dispute,1,2,
+chargeback,1,2,
+deposit,2,3,1
+deposit,3,4,1
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new().with_lock_reason_column();
+    engine.apply_csv_reader(reader).unwrap();
+    engine.freeze(2).unwrap();
+    assert_eq!(engine.freeze(4), Err(WarningKind::ClientNotFound(4)));
+    let clients = engine.clients();
+    assert_eq!(
+        clients.get(&1).unwrap().lock_reason,
+        Some(LockReason::Chargeback { tx: 2 })
+    );
+    assert_eq!(
+        clients.get(&2).unwrap().lock_reason,
+        Some(LockReason::Admin)
+    );
+    assert_eq!(clients.get(&3).unwrap().lock_reason, None);
+
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let mut lines: Vec<&str> = output.lines().collect();
+    lines[1..].sort_unstable();
+    assert_eq!(
+        lines,
+        vec![
+            "client,available,held,total,locked,lock_reason",
+            "1,5,0,5,true,chargeback:2",
+            "2,1,0,1,true,admin",
+            "3,1,0,1,false,",
+        ]
+    );
+}
+
+#[test]
+fn test_redispute_lock() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+deposit,1,2,3
+deposit,2,3,4
+dispute,1,1,
+dispute,1,1,
+dispute,2,3,
+dispute,2,1,
+dispute,2,1,
+dispute,1,1,
+deposit,1,4,1
+"#;
+    for limit in [None, Some(2)] {
+        let reader = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes());
+        let mut engine = TransactionEngine::new();
+        if let Some(limit) = limit {
+            engine = engine.with_redispute_lock(limit);
+        }
+        engine.apply_csv_reader(reader).unwrap();
+        // Mismatched clients are rejected before the transaction's dispute
+        // state is looked at
+        assert_eq!(engine.warning_counts()["already_disputed"], 2);
+        assert_eq!(engine.warning_counts()["client_mismatch"], 2);
+        let clients = engine.clients();
+        // Client 2 only tried to dispute client 1's transaction
+        assert!(!clients.get(&2).unwrap().locked);
+        let client = clients.get(&1).unwrap();
+        assert_eq!(client.held, 5.into());
+        match limit {
+            None => {
+                assert!(!client.locked);
+                assert_eq!(client.available, 4.into());
+            }
+            Some(_) => {
+                assert!(client.locked);
+                assert_eq!(
+                    client.lock_reason,
+                    Some(LockReason::RepeatedDisputes { tx: 1 })
+                );
+                assert_eq!(client.available, 3.into());
+            }
+        }
+    }
+    assert_eq!(
+        LockReason::from_str("repeated_disputes:1"),
+        Ok(LockReason::RepeatedDisputes { tx: 1 })
+    );
+}
+
+#[test]
+fn test_adjustments() {
+    let input = r#"type,client,tx,amount,note
+deposit,1,1,5,
+adjustment,1,2,2.5,duplicate fee refund
+adjustment,1,3,-3,
+adjustment,1,4,-5,overdraws
+dispute,1,2,,
+deposit,1,3,1,
+adjustment,2,5,1,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert_eq!(
+        engine.clients().get(&1).unwrap().available,
+        Amount::from_str("4.5").unwrap()
+    );
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::InsufficientFunds {
+                client: 1,
+                requested: 5.into(),
+                available: Amount::from_str("4.5").unwrap(),
+            },
+            WarningKind::TransactionNotFound(2),
+            WarningKind::TransactionExists(3),
+            WarningKind::ClientNotFound(2),
+        ]
+    );
+    let adjustments: Vec<(TxId, Amount, &str)> = engine
+        .adjustments()
+        .iter()
+        .map(|(tx, adjustment)| (*tx, adjustment.amount, adjustment.note.as_str()))
+        .collect();
+    assert_eq!(
+        adjustments,
+        vec![
+            (2, Amount::from_str("2.5").unwrap(), "duplicate fee refund"),
+            (3, Amount::from_str("-3").unwrap(), ""),
+        ]
+    );
+    assert!(engine.verify_integrity().is_ok());
+
+    engine.freeze(1).unwrap();
+    assert_eq!(
+        engine.apply_adjustment(6, 1, 1.into(), "after freeze"),
+        Err(EngineError::ClientLocked(1))
+    );
+    let mut engine = TransactionEngine::new().with_adjustments_forbidden();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 5.into(),
+    });
+    assert_eq!(
+        engine.apply_adjustment(2, 1, 1.into(), "forbidden"),
+        Err(EngineError::Rejected(WarningKind::AdjustmentsForbidden(2)))
+    );
+    assert!(engine.adjustments().is_empty());
+}
+
+#[test]
+fn test_client_trace() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+deposit,2,2,3
+withdrawal,1,3,2
+withdrawal,1,4,7
+dispute,1,1,
+dispute,2,2,
+resolve,1,1,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new().with_client_trace(1);
+    engine.apply_csv_reader(reader).unwrap();
+    let mut output = Vec::new();
+    engine.write_trace(&mut output).unwrap();
+    // The rejected withdrawal and client 2's operations aren't traced
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        r#"type,tx,available,held
+deposit,1,5,0
+withdrawal,3,3,0
+dispute,1,0,3
+resolve,1,3,0
+"#
+    );
+}
+
+#[test]
+fn test_disputes_report() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+deposit,1,2,3
+deposit,2,3,4
+withdrawal,2,4,1
+dispute,1,1,
+resolve,1,1,
+dispute,2,3,
+dispute,1,2,
+chargeback,1,2,
+dispute,1,1,
+dispute,1,5,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new().with_dispute_tracking();
+    engine.apply_csv_reader(reader).unwrap();
+    let mut output = Vec::new();
+    engine.write_disputes_report(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        r#"tx,client,amount,outcome
+1,1,5,resolved
+3,2,3,open
+2,1,3,chargedback
+"#
+    );
+}
+
+#[test]
+fn test_locked() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+deposit,1,3,4.0
+deposit,1,4,1.0
+dispute,1,4,
+dispute,1,2,
+chargeback,1,4,
+deposit,1,7,100
+deposit,1,8,15
+withdrawal,1,9,7
+dispute,1,9,
+chargeback,1,9
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 9.into());
+    assert_eq!(client.held, 3.into());
+    assert!(client.locked);
+}
+
+#[test]
+fn test_multiple_clients() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,1.3
+withdrawal,1,5,1.1
+deposit,3,4,7.0
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 3);
+    let client1 = clients.get(&1).unwrap();
+    let client2 = clients.get(&2).unwrap();
+    let client3 = clients.get(&3).unwrap();
+    assert_eq!(client1.available, Amount::from_str("2.6").unwrap());
+    assert_eq!(client2.available, 3.into());
+    assert_eq!(client3.available, 7.into());
+}
+
+#[test]
+fn test_csv_output_sorted() {
+    let input = r#"type,client,tx,amount
+deposit,3,1,3
+deposit,1,2,1
+deposit,2,3,2
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n\
+         1,1,0,1,false\n\
+         2,2,0,2,false\n\
+         3,3,0,3,false\n"
+    );
+}
+
+#[cfg(feature = "fixed-hasher")]
+#[test]
+fn test_fixed_hasher_iteration_order() {
+    let run = || {
+        let mut engine = TransactionEngine::new();
+        for i in 1..=100 {
+            engine.apply(Operation::Deposit {
+                tx: i,
+                client: (i * 37 % 101) as ClientId,
+                amount: 1.into(),
+            });
+        }
+        let clients: Vec<ClientId> = engine.clients().keys().collect();
+        let transactions: Vec<TxId> = engine.transactions.keys().copied().collect();
+        (clients, transactions)
+    };
+    assert_eq!(run(), run());
+}
+
+#[test]
+fn test_dense_client_store() {
+    let mut input = String::from("type,client,tx,amount\n");
+    for client in 1..=500 {
+        let tx = client * 3;
+        input += &format!("deposit,{},{},{}\n", client, tx, client % 7 + 1);
+        input += &format!("withdrawal,{},{},1\n", client, tx + 1);
+        if client % 5 == 0 {
+            input += &format!("dispute,{},{},\n", client, tx);
+        }
+        if client % 10 == 0 {
+            input += &format!("chargeback,{},{},\n", client, tx);
+        }
+    }
+    let run = |engine: TransactionEngine| {
+        let mut engine = engine;
+        let reader = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes());
+        engine.apply_csv_reader(reader).unwrap();
+        let mut output = Vec::new();
+        engine.to_csv_writer(&mut output).unwrap();
+        (engine, output)
+    };
+    let (map, map_output) = run(TransactionEngine::new());
+    let (dense, dense_output) = run(TransactionEngine::new().with_dense_client_store());
+    assert!(matches!(dense.clients(), ClientStore::Dense(_)));
+    assert_eq!(dense.clients().len(), 500);
+    assert_eq!(dense.clients(), map.clients());
+    assert_eq!(dense_output, map_output);
+    assert_eq!(dense.warnings(), map.warnings());
+}
+
+#[test]
+fn test_dense_clients_serde() {
+    let mut clients = DenseClients::new();
+    clients.insert(3, ClientInfo::new(1.into()));
+    clients.insert(7, ClientInfo::new(1.into()));
+    assert_eq!(clients.len(), 2);
+    let json = serde_json::to_string(&clients).unwrap();
+    // Only slots are serialized, the number of clients is counted when restored
+    let restored: DenseClients = serde_json::from_str(&json).unwrap();
+    assert_eq!(restored.len(), 2);
+    assert_eq!(
+        restored.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+        [3, 7]
+    );
+}
+
+#[test]
+fn test_anonymized_output() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+deposit,3,3,1.5
+dispute,2,2,
+deposit,258,4,7.25
+"#;
+    let output = |engine: TransactionEngine| {
+        let reader = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes());
+        let mut engine = engine;
+        engine.apply_csv_reader(reader).unwrap();
+        let mut output = Vec::new();
+        engine.to_csv_writer(&mut output).unwrap();
+        let mut map = Vec::new();
+        engine.write_anonymization_map(&mut map).unwrap();
+        (
+            String::from_utf8(output).unwrap(),
+            String::from_utf8(map).unwrap(),
+        )
+    };
+    let (plain, _) = output(TransactionEngine::new());
+    let (anonymized, map) = output(TransactionEngine::new().with_anonymized_output(42));
+    assert_ne!(anonymized, plain);
+    assert_eq!(
+        output(TransactionEngine::new().with_anonymized_output(42)),
+        (anonymized.clone(), map.clone())
+    );
+    assert_ne!(
+        output(TransactionEngine::new().with_anonymized_output(43)).0,
+        anonymized
+    );
+
+    // Mapping pseudonyms back gives the plain output, rows aside
+    let pseudonyms: HashMap<String, String> = map
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let (client, pseudonym) = line.split_once(',').unwrap();
+            (pseudonym.to_string(), client.to_string())
+        })
+        .collect();
+    assert_eq!(pseudonyms.len(), 4);
+    let mut restored: Vec<String> = anonymized
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let (pseudonym, balances) = line.split_once(',').unwrap();
+            format!("{},{}", pseudonyms[pseudonym], balances)
+        })
+        .collect();
+    restored.sort_by_key(|line| line.split(',').next().unwrap().parse::<u16>().unwrap());
+    assert_eq!(restored, plain.lines().skip(1).collect::<Vec<_>>());
+
+    // No two clients share a pseudonym
+    let all: HashSet<ClientId> = (0..=ClientId::MAX).map(|id| pseudonym(42, id)).collect();
+    assert_eq!(all.len(), usize::from(ClientId::MAX) + 1);
+}
+
+#[test]
+fn test_csv_output_into_buffer() {
+    let mut engine = TransactionEngine::new();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 2,
+        amount: 3.into(),
+    });
+    engine.apply(Operation::Deposit {
+        tx: 2,
+        client: 1,
+        amount: 5.into(),
+    });
+    engine.apply(Operation::Dispute { tx: 2, client: 1 });
+    // Writers can be passed by reference and reused afterwards
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    let expected: &[u8] = b"client,available,held,total,locked\n1,0,5,5,false\n2,3,0,3,false\n";
+    assert_eq!(output, expected);
+    engine.to_csv_writer(&mut output).unwrap();
+    assert_eq!(output, [expected, expected].concat());
+}
+
+#[test]
+fn test_buffered_csv_output() {
+    let mut engine = TransactionEngine::new();
+    for client in 1..=1000 {
+        engine.apply(Operation::Deposit {
+            tx: client.into(),
+            client,
+            amount: 1.into(),
+        });
+    }
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    let mut buffered = Vec::new();
+    engine
+        .to_csv_writer(io::BufWriter::new(&mut buffered))
+        .unwrap();
+    assert_eq!(output.iter().filter(|&&byte| byte == b'\n').count(), 1001);
+    assert_eq!(buffered, output);
+}
+
+#[test]
+fn test_json_output() {
+    let input = r#"type,client,tx,amount
+deposit,2,1,5
+deposit,1,2,3
+withdrawal,1,3,1
+deposit,3,4,4
+dispute,3,4,
+chargeback,3,4,
+deposit,2,5,2
+dispute,2,5,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let mut output = Vec::new();
+    engine.to_json(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        concat!(
+            r#"[{"client":1,"available":"2","held":"0","total":"2","locked":false},"#,
+            r#"{"client":2,"available":"5","held":"2","total":"7","locked":false},"#,
+            r#"{"client":3,"available":"0","held":"0","total":"0","locked":true}]"#,
+            "\n"
+        )
+    );
+}
+
+#[test]
+fn test_to_value() {
+    let mut engine = TransactionEngine::new();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 5.into(),
+    });
+    engine.apply(Operation::Deposit {
+        tx: 2,
+        client: 1,
+        amount: 3.into(),
+    });
+    engine.apply(Operation::Dispute { tx: 2, client: 1 });
+    assert_eq!(
+        engine.to_value(),
+        serde_json::json!({
+            "clients": [
+                {
+                    "client": 1,
+                    "available": "5",
+                    "held": "3",
+                    "total": "8",
+                    "locked": false,
+                    "lock_reason": null,
+                },
+            ],
+            "transactions": [
+                { "tx": 1, "type": "deposit", "client": 1, "amount": "5", "state": "" },
+                { "tx": 2, "type": "deposit", "client": 1, "amount": "3", "state": "disputed" },
+            ],
+            "disputes": [{ "tx": 2, "client": 1, "amount": "3" }],
+        })
+    );
+}
+
+#[test]
+fn test_multiple_clients_fixed_point() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,1.3
+withdrawal,1,5,1.1
+deposit,3,4,7.0
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new().with_fixed_point_output();
+    engine.apply_csv_reader(reader).unwrap();
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let mut lines: Vec<&str> = output.lines().collect();
+    lines[2..].sort_unstable();
+    assert_eq!(
+        lines,
+        vec![
+            "# scale=4",
+            "client,available,held,total,locked",
+            "1,26000,0,26000,false",
+            "2,30000,0,30000,false",
+            "3,70000,0,70000,false",
+        ]
+    );
+}
+
+#[test]
+fn test_client_filter() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,1.3
+withdrawal,1,5,1.1
+deposit,3,4,7.0
+dispute,1,1,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new().with_client_filter(std::iter::once(1).collect());
+    engine.apply_csv_reader(reader).unwrap();
+    assert!(engine.warnings().is_empty());
+    let clients = engine.clients();
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 0.into());
+    assert_eq!(client.held, Amount::from_str("2.6").unwrap());
+}
+
+#[test]
+fn test_alternating_clients() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+deposit,1,2,1
+deposit,2,3,2
+withdrawal,1,4,3
+deposit,2,5,4
+withdrawal,2,6,1
+withdrawal,1,7,3
+deposit,1,8,0.5
+"#;
+    let clients = test_clients(input);
+    assert_eq!(
+        clients.get(&1).unwrap().available,
+        Amount::from_str("0.5").unwrap()
+    );
+    assert_eq!(clients.get(&2).unwrap().available, 5.into());
+}
+
+#[test]
+fn test_zero_scale() {
+    let five = Amount::from_str("5.0000").unwrap();
+    let int_zero: Amount = 0.into();
+    assert_eq!(int_zero, zero());
+    assert!(int_zero < five);
+    assert_eq!(five - five, int_zero);
+    assert_eq!(int_zero + five, five);
+    assert_eq!(five - int_zero, five);
+    #[cfg(not(feature = "minor-units-core"))]
+    {
+        assert_eq!(zero().scale(), DECIMAL_PLACES);
+        assert_eq!((int_zero + five).scale(), DECIMAL_PLACES);
+        assert_eq!((five - five).to_string(), "0.0000");
+    }
+
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0000
+withdrawal,1,2,5
+dispute,1,1,
+withdrawal,1,3,0
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, vec![WarningKind::FullyWithdrawn(1)]);
+    assert_eq!(engine.clients().get(&1).unwrap().available, 0.into());
+}
+
+#[test]
+fn test_precision() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.1234
+deposit,1,2,1.4321
+withdrawal,1,3,1.1111
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, Amount::from_str("5.4444").unwrap());
+    assert_eq!(client.held, 0.into());
+    assert!(!client.locked);
+}
+
+#[cfg(not(feature = "minor-units-core"))]
+#[test]
+fn test_output_rounding() {
+    // Input amounts with more than 4 decimal places are rejected, but resumed
+    // balances are taken as they are
+    let balances = r#"client,available,held,total,locked
+1,5.12345,0,5.12345,false
+2,5.12355,0,5.12355,false
+3,0.0000,0,0.0000,false
+4,2.5,0,2.5,false
+"#;
+    let mut journal = Vec::new();
+    TransactionEngine::new()
+        .write_journal(&mut journal)
+        .unwrap();
+    let mut engine = TransactionEngine::new();
+    engine
+        .resume(
+            ReaderBuilder::new().from_reader(balances.as_bytes()),
+            ReaderBuilder::new().from_reader(journal.as_slice()),
+        )
+        .unwrap();
+    // Balances keep the full precision, only the output is rounded
+    assert_eq!(
+        engine.clients().get(&1).unwrap().available,
+        Amount::from_str("5.12345").unwrap()
+    );
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let mut lines: Vec<&str> = output.lines().skip(1).collect();
+    lines.sort_unstable();
+    assert_eq!(
+        lines,
+        vec![
+            "1,5.1234,0,5.1234,false",
+            "2,5.1236,0,5.1236,false",
+            "3,0,0,0,false",
+            "4,2.5,0,2.5,false",
+        ]
+    );
+    let mut output = Vec::new();
+    engine.to_json(&mut output).unwrap();
+    assert!(String::from_utf8(output)
+        .unwrap()
+        .starts_with(r#"[{"client":1,"available":"5.1234","held":"0","total":"5.1234""#));
+}
+
+#[test]
+fn test_split_by_weights() {
+    let amount = Amount::from_str("10.0001").unwrap();
+    let parts = split_by_weights(amount, &[1, 1, 1]).unwrap();
+    assert_eq!(
+        parts,
+        vec![
+            Amount::from_str("3.3334").unwrap(),
+            Amount::from_str("3.3334").unwrap(),
+            Amount::from_str("3.3333").unwrap(),
+        ]
+    );
+    assert_eq!(
+        parts.into_iter().fold(zero(), |sum, part| sum + part),
+        amount
+    );
+
+    // The leftover unit goes to the part with the largest remainder
+    let parts = split_by_weights(Amount::from_str("-0.0010").unwrap(), &[1, 2, 0, 4]).unwrap();
+    assert_eq!(
+        parts,
+        vec![
+            Amount::from_str("-0.0001").unwrap(),
+            Amount::from_str("-0.0003").unwrap(),
+            zero(),
+            Amount::from_str("-0.0006").unwrap(),
+        ]
+    );
+    assert!(split_by_weights(amount, &[0, 0]).is_err());
+    assert!(split_by_weights(amount, &[]).is_err());
+}
+
+#[test]
+fn test_too_many_decimal_places() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.12345
+deposit,1,2,5.1234
+deposit,1,3,1.00000
+withdrawal,1,4,0.00001
+dispute,1,2,
+resolve,1,2,
+dispute,1,3,
+chargeback,1,3,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, Amount::from_str("5.1234").unwrap());
+    assert_eq!(client.held, 0.into());
+    assert!(client.locked);
+    #[cfg(not(feature = "minor-units-core"))]
+    let expected = vec![
+        WarningKind::TooManyDecimalPlaces { tx: 1, scale: 5 },
+        WarningKind::TooManyDecimalPlaces { tx: 4, scale: 5 },
+    ];
+    #[cfg(feature = "minor-units-core")]
+    let expected = vec![
+        WarningKind::InvalidAmount("Amount 5.12345 has more than 4 decimal places".into()),
+        WarningKind::InvalidAmount("Amount 0.00001 has more than 4 decimal places".into()),
+    ];
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, expected);
+}
+
+#[test]
+fn test_dispute_unresolved_withdrawal() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+deposit,1,3,4.0
+withdrawal,1,4,8.0
+dispute,1,4,
+dispute,1,2,
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 9.into());
+    assert_eq!(client.held, Amount::from_str("-5").unwrap());
+    assert!(!client.locked);
+}
+
+#[test]
+fn test_dispute_resolve_withdrawal() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+deposit,1,3,4.0
+withdrawal,1,4,8.0
+dispute,1,4,
+dispute,1,2,
+resolve,1,4,
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 1.into());
+    assert_eq!(client.held, 3.into());
+    assert!(!client.locked);
+}
+
+#[test]
+fn test_dispute_chargeback_withdrawal() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+deposit,1,3,4.0
+withdrawal,1,4,8.0
+dispute,1,4,
+dispute,1,2,
+chargeback,1,4,
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 9.into());
+    assert_eq!(client.held, 3.into());
+    assert!(client.locked);
+}
+
+#[test]
+fn test_strict_dispute_amounts() {
+    let mut engine = TransactionEngine::new().with_strict_dispute_amounts();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 10.into(),
+    });
+    engine.apply(Operation::Withdrawal {
+        tx: 2,
+        client: 1,
+        amount: 4.into(),
+    });
+    // Only the 6 which weren't withdrawn get disputed
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    assert_eq!(engine.clients()[&1].held, 6.into());
+    let available = engine.clients()[&1].available;
+    engine.apply(Operation::Resolve { tx: 1, client: 1 });
+    let info = &engine.clients()[&1];
+    assert_eq!(info.available - available, 6.into());
+    assert_eq!(info.held, 0.into());
+    assert!(engine.warnings().is_empty());
+
+    // State without the recorded amount, e.g. from an older snapshot,
+    // would fall back to releasing the full 10
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    let mut snapshot = engine.snapshot();
+    for (_, state) in snapshot.transactions.iter_mut() {
+        state.dispute_amount = None;
+    }
+    let mut engine = TransactionEngine::new().with_strict_dispute_amounts();
+    engine.apply_delta(&snapshot);
+    engine.apply(Operation::Chargeback { tx: 1, client: 1 });
+    let info = &engine.clients()[&1];
+    assert_eq!(info.held, 6.into());
+    assert!(!info.locked);
+    assert_eq!(
+        engine.warnings()[0].kind,
+        WarningKind::DisputeAmountUnknown(1)
+    );
+}
+
+#[test]
+fn test_strict_dispute_amounts_drift() {
+    let setup = || {
+        let mut engine = TransactionEngine::new().with_strict_dispute_amounts();
+        for (tx, amount) in [(1, 5), (2, 3)] {
+            engine.apply(Operation::Deposit {
+                tx,
+                client: 1,
+                amount: amount.into(),
+            });
+            engine.apply(Operation::Dispute { tx, client: 1 });
+        }
+        engine
+    };
+    let mismatch = |moved: i32| WarningKind::DisputeAmountMismatch {
+        tx: 1,
+        disputed: 5.into(),
+        moved: moved.into(),
+    };
+
+    // Held funds drifted away from the disputed amounts
+    let mut engine = setup();
+    engine.clients.get_mut(&1).unwrap().held += Amount::from(1);
+    engine.apply(Operation::Resolve { tx: 1, client: 1 });
+    assert_eq!(engine.warnings()[0].kind, mismatch(6));
+    assert!(engine.is_disputed(1));
+
+    // The other dispute's recorded amount drifted
+    let mut engine = setup();
+    engine.dispute_amounts.insert(2, 2.into());
+    engine.apply(Operation::Chargeback { tx: 1, client: 1 });
+    assert_eq!(engine.warnings()[0].kind, mismatch(6));
+    assert!(!engine.clients()[&1].locked);
+
+    // Consistent bookkeeping releases both disputes
+    let mut engine = setup();
+    engine.apply(Operation::Resolve { tx: 1, client: 1 });
+    engine.apply(Operation::Chargeback { tx: 2, client: 1 });
+    assert!(engine.warnings().is_empty());
+    assert_eq!(engine.clients()[&1].held, 0.into());
+}
+
+#[test]
+fn test_withdrawal_dispute_comparisons() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,8
+withdrawal,1,3,2
+dispute,1,2,
+dispute,1,3,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    // Disputing withdrawals gives the withdrawn funds back, even though
+    // nothing was available, which is intended
+    let client = &engine.clients()[&1];
+    assert_eq!(client.available, 10.into());
+    assert_eq!(client.held, (-10).into());
+
+    // Held funds of -10 cover resolving the dispute of -8
+    engine.apply(Operation::Resolve { tx: 2, client: 1 });
+    assert!(engine.warnings().is_empty());
+    let client = &engine.clients()[&1];
+    assert_eq!(client.available, 2.into());
+    assert_eq!(client.held, (-2).into());
+
+    // Resolving would take back 2 of the 1 still available
+    engine.apply(Operation::Withdrawal {
+        tx: 4,
+        client: 1,
+        amount: 1.into(),
+    });
+    engine.apply(Operation::Resolve { tx: 3, client: 1 });
+    assert_eq!(
+        engine.warnings()[0].kind,
+        WarningKind::InsufficientFunds {
+            client: 1,
+            requested: 2.into(),
+            available: 1.into(),
+        }
+    );
+    assert!(engine.is_disputed(3));
+    let client = &engine.clients()[&1];
+    assert_eq!(client.available, 1.into());
+    assert_eq!(client.held, (-2).into());
+
+    engine.apply(Operation::Chargeback { tx: 3, client: 1 });
+    let client = &engine.clients()[&1];
+    assert_eq!(client.available, 1.into());
+    assert_eq!(client.held, 0.into());
+    assert!(client.locked);
+    assert_eq!(engine.warnings().len(), 1);
+}
+
+#[test]
+fn test_dispute_policy() {
+    let prefix = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+deposit,1,3,4.0
+withdrawal,1,4,8.0
+dispute,1,4,
+dispute,1,2,
+"#;
+    let fixtures = [
+        ("", (9, -5), false),
+        ("resolve,1,4,\n", (1, 3), false),
+        ("chargeback,1,4,\n", (9, 3), true),
+    ];
+    for policy in [DisputePolicy::AllowWithdrawals, DisputePolicy::DepositsOnly] {
+        for (suffix, (available, held), locked) in fixtures.iter() {
+            let input = format!("{}{}", prefix, suffix);
+            let reader = ReaderBuilder::new()
+                .flexible(true)
+                .from_reader(input.as_bytes());
+            let mut engine = TransactionEngine::new().with_policy(policy);
+            engine.apply_csv_reader(reader).unwrap();
+            let client = engine.clients().get(&1).unwrap();
+            let kinds: Vec<WarningKind> =
+                engine.warnings().iter().map(|w| w.kind.clone()).collect();
+            match policy {
+                DisputePolicy::AllowWithdrawals => {
+                    assert_eq!(client.available, Amount::from(*available));
+                    assert_eq!(client.held, Amount::from(*held));
+                    assert_eq!(client.locked, *locked);
+                    assert!(kinds.is_empty(), "{:?}", kinds);
+                }
+                // Deposit 2 was fully withdrawn, so nothing gets disputed
+                DisputePolicy::DepositsOnly => {
+                    assert_eq!(client.available, 4.into());
+                    assert_eq!(client.held, 0.into());
+                    assert!(!client.locked);
+                    assert_eq!(kinds[0], WarningKind::WithdrawalDisputeDisallowed(4));
+                    assert_eq!(kinds[1], WarningKind::FullyWithdrawn(2));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dispute_scope() {
+    let first = r#"type,client,tx,amount
+deposit,1,1,5.0
+"#;
+    let second = r#"type,client,tx,amount
+deposit,1,2,3.0
+dispute,1,1,
+dispute,1,2,
+"#;
+    for scope in [DisputeScope::Global, DisputeScope::PerFile] {
+        let mut engine = TransactionEngine::new().with_dispute_scope(scope);
+        for input in [first, second] {
+            let reader = ReaderBuilder::new()
+                .flexible(true)
+                .from_reader(input.as_bytes());
+            engine.apply_csv_reader(reader).unwrap();
+        }
+        assert!(engine.is_disputed(2));
+        let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+        match scope {
+            DisputeScope::Global => {
+                assert!(kinds.is_empty());
+                assert!(engine.is_disputed(1));
+            }
+            DisputeScope::PerFile => {
+                assert_eq!(kinds, vec![WarningKind::DisputeOutsideFile(1)]);
+                assert!(!engine.is_disputed(1));
+                assert_eq!(engine.clients()[&1].held, 3.into());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_incorrect_ops() {
+    let input = r#"type,client,tx,amount
+deposits,1,1,5.0
+withdraw,2,2,7.0
+withdrawx,3,3,5.1
+disputer,4,4,5.5
+resolv,4,4,5.5
+charge-back,4,4,5.5
+,,,
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 0);
+}
+
+#[test]
+fn test_apply_csv_stream() {
+    // Untrimmed and with a missing trailing field, as piped in through stdin
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 5\ndeposit, 1, 2, 3\ndispute, 1, 2\n";
+    let mut engine = TransactionEngine::new();
+    engine.apply_csv(input.as_bytes()).unwrap();
+    assert!(engine.warnings().is_empty(), "{:?}", engine.warnings());
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 5.into());
+    assert_eq!(client.held, 3.into());
+}
+
+#[test]
+fn test_chained_inputs() {
+    let first = "type,client,tx,amount\ndeposit,1,1,5\ndeposit,2,2,3\n";
+    // Reuses id 2 from the first input and disputes a deposit from it
+    let second = "type,client,tx,amount\ndeposit,1,2,7\ndispute,1,1,\nwithdrawal,2,3,1\n";
+    let mut engine = TransactionEngine::new();
+    engine.apply_csv(first.as_bytes()).unwrap();
+    engine.apply_csv(second.as_bytes()).unwrap();
+    assert_eq!(engine.rows_read(), 5);
+    let clients = engine.clients();
+    assert_eq!(clients.get(&1).unwrap().available, 0.into());
+    assert_eq!(clients.get(&1).unwrap().held, 5.into());
+    assert_eq!(clients.get(&2).unwrap().available, 2.into());
+    let warnings = engine.warnings();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].line, Some(2));
+    assert_eq!(warnings[0].kind, WarningKind::TransactionExists(2));
+}
+
+#[test]
+fn test_input_buffer_size() {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=100 {
+        input += &format!("deposit,{},{},1.5\n", tx % 3 + 1, tx);
+    }
+    input += "withdrawal,1,101,2\ndispute,3,2,\n";
+    let mut expected = TransactionEngine::new();
+    expected.apply_csv(input.as_bytes()).unwrap();
+    // Smaller than a single row
+    let mut engine = TransactionEngine::new().with_input_buffer_size(8);
+    engine.apply_csv(input.as_bytes()).unwrap();
+    assert_eq!(engine.clients(), expected.clients());
+    assert_eq!(engine.rows_read(), 102);
+    assert!(engine.is_disputed(2));
+}
+
+#[test]
+fn test_no_header() {
+    let input = "deposit,1,1,5\ndeposit,2,2,3\nwithdrawal,1,3,2\ndispute,2,2\n";
+    let reader = TransactionEngine::new()
+        .with_no_header()
+        .input_reader_builder()
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert!(engine.warnings().is_empty(), "{:?}", engine.warnings());
+    let clients = engine.clients();
+    assert_eq!(clients.get(&1).unwrap().available, 3.into());
+    assert_eq!(clients.get(&2).unwrap().available, 0.into());
+    assert_eq!(clients.get(&2).unwrap().held, 3.into());
+}
+
+#[test]
+fn test_unknown_type_rejected_at_parse_time() {
+    let input = r#"type,client,tx,amount
+deposits,1,1,5
+deposit,1,2,3
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert_eq!(engine.clients().get(&1).unwrap().available, 3.into());
+    let warnings = engine.warnings();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].line, Some(2));
+    assert!(
+        matches!(&warnings[0].kind, WarningKind::InvalidRow(e) if e.contains("deposits")),
+        "{:?}",
+        warnings[0]
+    );
+}
+
+#[test]
+fn test_blank_rows_skipped() {
+    let input = "type,client,tx,amount\ndeposit,1,1,5\n,,,\ndeposit,x,2,\n\n , ,\t, \n,,,\n\n";
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert_eq!(engine.clients().get(&1).unwrap().available, 5.into());
+    let warnings = engine.warnings();
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert_eq!(warnings[0].line, Some(4));
+    assert!(matches!(warnings[0].kind, WarningKind::InvalidRow(_)));
+}
+
+#[test]
+fn test_undisputed() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+resolve,1,2,1.0
+chargeback,2,3,1.0
+resolve,2,4,
+chargeback,2,5,
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 1.into());
+    assert!(!client.locked);
+}
+
+#[test]
+fn test_undisputed_warning_kinds() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+resolve,1,1,
+chargeback,1,1,
+resolve,1,1,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::ResolveNotDisputed(1),
+            WarningKind::ChargebackNotDisputed(1),
+            WarningKind::ResolveNotDisputed(1),
+        ]
+    );
+    let counts = engine.warning_counts();
+    assert_eq!(counts.len(), 2);
+    assert_eq!(counts["resolve_not_disputed"], 2);
+    assert_eq!(counts["chargeback_not_disputed"], 1);
+}
+
+#[test]
+fn test_apply_rows() {
+    let row = |op, client, tx, amount: Option<i64>| Row {
+        op,
+        client: Some(client),
+        external_id: None,
+        tx,
+        amount: amount.map(Decimal::from),
+        timestamp: None,
+        note: None,
+        target: None,
+    };
+    let mut engine = TransactionEngine::new();
+    engine.apply_rows(vec![
+        row(OperationType::Deposit, 1, 1, Some(5)),
+        row(OperationType::Deposit, 1, 2, Some(3)),
+    ]);
+    engine.apply_rows(vec![
+        row(OperationType::Dispute, 1, 2, None),
+        row(OperationType::Chargeback, 1, 2, None),
+    ]);
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available(), 5.into());
+    assert_eq!(client.held(), 0.into());
+    assert_eq!(client.total(), 5.into());
+    assert!(client.locked());
+    assert_eq!(client.lock_reason(), Some(LockReason::Chargeback { tx: 2 }));
+}
+
+#[test]
+fn test_client_query() {
+    let mut engine = TransactionEngine::new();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 5.into(),
+    });
+    let client = engine.client(1).unwrap();
+    assert_eq!(client.available(), 5.into());
+    assert_eq!(client.held(), 0.into());
+    assert!(!client.locked());
+    assert!(engine.client(2).is_none());
+}
+
+#[test]
+fn test_apply_channel() {
+    let (sender, receiver) = mpsc::sync_channel(2);
+    let producer = std::thread::spawn(move || {
+        for tx in 1..=100 {
+            let client = (tx % 3) as ClientId;
+            let amount = 2.into();
+            sender
+                .send(Operation::Deposit { tx, client, amount })
+                .unwrap();
+        }
+        sender
+            .send(Operation::Withdrawal {
+                tx: 101,
+                client: 1,
+                amount: 5.into(),
+            })
+            .unwrap();
+        sender
+            .send(Operation::Dispute { tx: 3, client: 0 })
+            .unwrap();
+    });
+    let mut engine = TransactionEngine::new();
+    engine.apply_channel(receiver);
+    producer.join().unwrap();
+    let clients = engine.clients();
+    assert_eq!(clients.get(&0).unwrap().available, 64.into());
+    assert_eq!(clients.get(&0).unwrap().held, 2.into());
+    assert_eq!(clients.get(&1).unwrap().available, 63.into());
+    assert_eq!(clients.get(&2).unwrap().available, 66.into());
+    assert!(engine.warnings().is_empty());
+}
+
+#[test]
+fn test_collect_rows() {
+    let row = |op, client, tx, amount: Option<&str>| Row {
+        op,
+        client: Some(client),
+        external_id: None,
+        tx,
+        amount: amount.map(|amount| Decimal::from_str(amount).unwrap()),
+        timestamp: None,
+        note: None,
+        target: None,
+    };
+    let rows = vec![
+        row(OperationType::Deposit, 1, 1, Some("5")),
+        row(OperationType::Deposit, 2, 2, Some("3")),
+        row(OperationType::Withdrawal, 1, 3, Some("1.5")),
+        row(OperationType::Dispute, 2, 2, None),
+        row(OperationType::Withdrawal, 2, 4, Some("1")),
+    ];
+    let engine: TransactionEngine = rows.into_iter().collect();
+    let clients = engine.clients();
+    assert_eq!(
+        clients.get(&1).unwrap().available,
+        Amount::from_str("3.5").unwrap()
+    );
+    assert_eq!(clients.get(&2).unwrap().held, 3.into());
+    assert_eq!(
+        engine.warnings(),
+        &[Warning {
+            seq: 0,
+            line: None,
+            kind: WarningKind::InsufficientFunds {
+                client: 2,
+                requested: 1.into(),
+                available: 0.into(),
+            },
+        }]
+    );
+}
+
+#[test]
+fn test_is_disputed() {
+    let mut engine = TransactionEngine::new();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 5.into(),
+    });
+    engine.apply(Operation::Deposit {
+        tx: 2,
+        client: 1,
+        amount: 3.into(),
+    });
+    assert!(!engine.is_disputed(1));
+
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    assert!(engine.is_disputed(1));
+    assert!(!engine.is_disputed(2));
+
+    engine.apply(Operation::Resolve { tx: 1, client: 1 });
+    assert!(!engine.is_disputed(1));
+}
+
+#[test]
+fn test_dispute_missing_client() {
+    let mut engine = TransactionEngine::new();
+    engine.transactions.insert(
+        1,
+        Transaction {
+            client: 1,
+            amount: 5.into(),
+        },
+    );
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    assert!(!engine.is_disputed(1));
+    assert_eq!(
+        engine.warnings().last().unwrap().kind,
+        WarningKind::ClientNotFound(1)
+    );
+}
+
+#[test]
+fn test_dispute_source() {
+    for source in [DisputeSource::AvailableOnly, DisputeSource::TotalFunds] {
+        let mut engine = TransactionEngine::new().with_dispute_source(source);
+        engine.apply(Operation::Deposit {
+            tx: 1,
+            client: 1,
+            amount: Amount::from_str("0.0005").unwrap(),
+        });
+        engine.clamp_dust(Amount::from_str("0.001").unwrap());
+        engine.apply(Operation::Deposit {
+            tx: 2,
+            client: 1,
+            amount: 1.into(),
+        });
+        engine.apply(Operation::Dispute { tx: 2, client: 1 });
+        // Deposit 1 still can be disputed, but there are no available funds left
+        engine.apply(Operation::Dispute { tx: 1, client: 1 });
+        let client = engine.clients().get(&1).unwrap();
+        match source {
+            DisputeSource::AvailableOnly => {
+                assert_eq!(client.available, 0.into());
+                assert_eq!(client.held, 1.into());
+                assert_eq!(
+                    engine.warnings().last().unwrap().kind,
+                    WarningKind::DisputeExceedsAvailable {
+                        tx: 1,
+                        amount: Amount::from_str("0.0005").unwrap(),
+                        available: 0.into(),
+                    }
+                );
+            }
+            DisputeSource::TotalFunds => {
+                assert_eq!(client.available, Amount::from_str("-0.0005").unwrap());
+                assert_eq!(client.held, Amount::from_str("1.0005").unwrap());
+                assert!(engine.warnings().is_empty());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_held_invariant() {
+    let mut engine = TransactionEngine::new()
+        .with_dispute_source(DisputeSource::TotalFunds)
+        .with_held_invariant();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: Amount::from_str("0.0005").unwrap(),
+    });
+    engine.clamp_dust(Amount::from_str("0.001").unwrap());
+    engine.apply(Operation::Deposit {
+        tx: 2,
+        client: 1,
+        amount: 1.into(),
+    });
+    engine.apply(Operation::Deposit {
+        tx: 3,
+        client: 2,
+        amount: 5.into(),
+    });
+    engine.apply(Operation::Withdrawal {
+        tx: 4,
+        client: 2,
+        amount: 2.into(),
+    });
+    engine.apply(Operation::Dispute { tx: 2, client: 1 });
+    let before = engine.snapshot();
+    // Held funds would exceed the total of 1
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    // Held funds would turn negative
+    engine.apply(Operation::Dispute { tx: 4, client: 2 });
+    let after = engine.snapshot();
+    assert_eq!(after.clients, before.clients);
+    assert_eq!(after.transactions, before.transactions);
+    assert!(!engine.is_disputed(1));
+    assert!(!engine.is_disputed(4));
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::HeldOutOfBounds {
+                tx: 1,
+                held: Amount::from_str("1.0005").unwrap(),
+                total: 1.into(),
+            },
+            WarningKind::HeldOutOfBounds {
+                tx: 4,
+                held: Amount::from_str("-2").unwrap(),
+                total: 3.into(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_dispute_rounded_deposit() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.00005
+dispute,1,1,
+"#;
+    for (strategy, rounded) in [
+        (RoundingStrategy::MidpointAwayFromZero, "1.0001"),
+        (RoundingStrategy::MidpointNearestEven, "1.0000"),
+    ] {
+        let reader = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes());
+        let mut engine = TransactionEngine::new().with_input_rounding(strategy);
+        engine.apply_csv_reader(reader).unwrap();
+        let client = engine.clients().get(&1).unwrap();
+        assert_eq!(client.available, 0.into());
+        assert_eq!(client.held, Amount::from_str(rounded).unwrap());
+        assert!(engine.is_disputed(1));
+    }
+}
+
+#[test]
+fn test_row_limit() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+bogus,1,2,1.0
+deposit,1,3,1.0
+deposit,2,4,1.0
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new().with_row_limit(2);
+    engine.apply_csv_reader(reader).unwrap();
+    let clients = engine.clients();
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients.get(&1).unwrap().available, 1.into());
+
+    // The limit holds across inputs, not per input
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    engine
+        .apply_ndjson(r#"{"type":"deposit","client":3,"tx":5,"amount":"1.0"}"#.as_bytes())
+        .unwrap();
+    assert_eq!(engine.rows_read(), 2);
+    assert_eq!(engine.clients().len(), 1);
+}
+
+#[test]
+fn test_sample_rate() {
+    let mut input = String::from("type,client,tx,amount\n");
+    for i in 1..=200 {
+        input += &format!("deposit,{},{},1.0\n", i, i);
+    }
+    let sample = |seed| {
+        let reader = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes());
+        let mut engine = TransactionEngine::new().with_sample_rate(0.5, seed);
+        engine.apply_csv_reader(reader).unwrap();
+        assert_eq!(engine.rows_read(), 200);
+        let mut clients: Vec<ClientId> = engine.clients().keys().collect();
+        clients.sort_unstable();
+        clients
+    };
+    let clients = sample(42);
+    assert_eq!(clients, sample(42));
+    assert_ne!(clients, sample(7));
+    assert!((70..=130).contains(&clients.len()), "{}", clients.len());
+}
+
+#[test]
+fn test_totals_match_applied_transactions() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+withdrawal,1,3,1.5
+withdrawal,1,4,100
+deposit,2,5,4.0
+withdrawal,2,6,2.5
+dispute,1,2,
+chargeback,1,2,
+dispute,2,6,
+chargeback,2,6,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert!(engine.inconsistent_totals().is_empty());
+    let clients = engine.clients();
+    assert_eq!(
+        clients.get(&1).unwrap().total(),
+        Amount::from_str("3.5").unwrap()
+    );
+    assert_eq!(clients.get(&2).unwrap().total(), 4.into());
+}
+
+#[test]
+fn test_totals_check_large_amounts() {
+    // Deposits of client 1 add up to more than fits in an amount, while the
+    // transfer in between keeps its total in range
+    let input = r#"type,client,tx,amount,target
+deposit,1,1,50000000000000000000000000000,
+transfer,1,2,50000000000000000000000000000,2
+deposit,1,3,50000000000000000000000000000,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert!(engine.warnings().is_empty());
+    assert!(engine.verify_integrity().is_ok());
+    let mut restored = TransactionEngine::new();
+    restored.apply_delta(&engine.snapshot());
+    assert!(restored.verify_integrity().is_ok());
+}
+
+#[test]
+fn test_apply_operations() {
+    let mut engine = TransactionEngine::new();
+    let ops = [
+        Operation::Deposit {
+            tx: 1,
+            client: 1,
+            amount: 5.into(),
+        },
+        Operation::Withdrawal {
+            tx: 2,
+            client: 1,
+            amount: 2.into(),
+        },
+        Operation::Deposit {
+            tx: 3,
+            client: 2,
+            amount: 4.into(),
+        },
+        Operation::Dispute { tx: 3, client: 2 },
+        Operation::Chargeback { tx: 3, client: 2 },
+        Operation::Deposit {
+            tx: 4,
+            client: 2,
+            amount: 1.into(),
+        },
+    ];
+    for op in ops {
+        engine.apply(op);
+    }
+    let clients = engine.clients();
+    let client1 = clients.get(&1).unwrap();
+    assert_eq!(client1.available, 3.into());
+    assert!(!client1.locked);
+    let client2 = clients.get(&2).unwrap();
+    assert_eq!(client2.total(), 0.into());
+    assert!(client2.locked);
+}
+
+#[test]
+fn test_empty_amount_cells() {
+    let input = "type,client,tx,amount
+deposit,1,1,5.0
+dispute,1,1,
+resolve,1,1,   
+dispute,1,1,\t
+deposit,1,2, 2.5 
+";
+    let clients = test_clients(input);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, Amount::from_str("2.5").unwrap());
+    assert_eq!(client.held, 5.into());
+}
+
+#[test]
+fn test_garbage_amount_cell() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,five
+withdrawal,1,3,1.2.3
+"#;
+    let clients = test_clients(input);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 5.into());
+
+    let mut reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let errors: Vec<String> = reader
+        .deserialize::<Row>()
+        .filter_map(|row| row.err())
+        .map(|e| e.to_string())
+        .collect();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].contains("invalid amount \"five\""));
+    assert!(errors[1].contains("invalid amount \"1.2.3\""));
+}
+
+#[test]
+fn test_drain_warnings() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,7.0
+dispute,1,3,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert_eq!(
+        engine.drain_warnings(),
+        vec![
+            Warning {
+                seq: 0,
+                line: Some(3),
+                kind: WarningKind::InsufficientFunds {
+                    client: 1,
+                    requested: 7.into(),
+                    available: 5.into(),
+                },
+            },
+            Warning {
+                seq: 1,
+                line: Some(4),
+                kind: WarningKind::TransactionNotFound(3),
+            },
+        ]
+    );
+    assert!(engine.warnings().is_empty());
+
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,4,1.0
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    assert_eq!(
+        engine.drain_warnings(),
+        vec![Warning {
+            seq: 2,
+            line: Some(2),
+            kind: WarningKind::TransactionExists(1),
+        }]
+    );
+    assert!(engine.drain_warnings().is_empty());
+}
+
+#[test]
+fn test_clamp_dust() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,0.0001
+dispute,1,2,
+deposit,2,3,0.0005
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let held = engine.clients().get(&1).unwrap().held;
+    assert_eq!(held, Amount::from_str("-0.0001").unwrap());
+
+    let clamped = engine.clamp_dust(Amount::from_str("0.001").unwrap());
+    let mut clamped = clamped;
+    clamped.sort_by_key(|dust| dust.client);
+    let expected = vec![
+        ClampedDust {
+            client: 1,
+            balance: Balance::Held,
+            amount: held,
+        },
+        ClampedDust {
+            client: 2,
+            balance: Balance::Available,
+            amount: Amount::from_str("0.0005").unwrap(),
+        },
+    ];
+    assert_eq!(clamped, expected);
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 1.into());
+    assert_eq!(client.held, 0.into());
+    assert_eq!(engine.clients().get(&2).unwrap().available, 0.into());
+
+    let mut recorded = engine.clamped_dust().to_vec();
+    recorded.sort_by_key(|dust| dust.client);
+    assert_eq!(recorded, expected);
+    assert!(engine.inconsistent_totals().is_empty());
+}
+
+#[test]
+fn test_apply_operation_outcome() {
+    let mut engine = TransactionEngine::new();
+    let outcome = engine.apply_operation(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 5.into(),
+    });
+    assert_eq!(
+        outcome,
+        Ok(OpOutcome {
+            client: 1,
+            available_delta: 5.into(),
+            held_delta: 0.into(),
+            locked: false,
+            lost_precision: false,
+        })
+    );
+    let outcome = engine.apply_operation(Operation::Dispute { tx: 1, client: 1 });
+    assert_eq!(
+        outcome,
+        Ok(OpOutcome {
+            client: 1,
+            available_delta: (-5).into(),
+            held_delta: 5.into(),
+            locked: false,
+            lost_precision: false,
+        })
+    );
+    engine
+        .apply_operation(Operation::Chargeback { tx: 1, client: 1 })
+        .unwrap();
+
+    engine.apply(Operation::Deposit {
+        tx: 2,
+        client: 2,
+        amount: 1.into(),
+    });
+    assert_eq!(
+        engine.apply_operation(Operation::Dispute { tx: 2, client: 1 }),
+        Err(EngineError::ClientLocked(1))
+    );
+    assert!(engine.warnings().is_empty());
+}
+
+#[test]
+fn test_reject_reasons() {
+    let mut engine = TransactionEngine::new();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 2.into(),
+    });
+    assert_eq!(
+        engine.apply_operation(Operation::Withdrawal {
+            tx: 2,
+            client: 1,
+            amount: 3.into(),
+        }),
+        Err(EngineError::InsufficientFunds {
+            client: 1,
+            requested: 3.into(),
+            available: 2.into(),
+        })
+    );
+    assert_eq!(
+        engine.apply_operation(Operation::Dispute { tx: 3, client: 1 }),
+        Err(EngineError::TransactionNotFound(3))
+    );
+    assert_eq!(
+        engine.apply_operation(Operation::Resolve { tx: 1, client: 1 }),
+        Err(EngineError::Rejected(WarningKind::ResolveNotDisputed(1)))
+    );
+    // Rejections are returned to the caller rather than recorded
+    assert!(engine.warnings().is_empty());
+    assert_eq!(engine.clients().get(&1).unwrap().available, 2.into());
+}
+
+#[test]
+fn test_dispute_unknown_client() {
+    let mut engine = TransactionEngine::new();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 5.into(),
+    });
+    let before = engine.snapshot();
+    for op in [
+        Operation::Dispute { tx: 1, client: 9 },
+        Operation::Resolve { tx: 1, client: 9 },
+        Operation::Chargeback { tx: 1, client: 9 },
+    ] {
+        assert_eq!(engine.valid(&op), Err(WarningKind::ClientNotFound(9)));
+        engine.apply(op);
+    }
+    // Rejected by validation, before taking a sequence number
+    assert_eq!(engine.snapshot(), before);
+    assert!(engine.clients().get(&9).is_none());
+    assert!(!engine.is_disputed(1));
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, vec![WarningKind::ClientNotFound(9); 3]);
+}
+
+#[test]
+fn test_dispute_client_mismatch() {
+    let mut engine = TransactionEngine::new();
+    for (tx, client) in [(1, 1), (2, 2)] {
+        engine.apply(Operation::Deposit {
+            tx,
+            client,
+            amount: 5.into(),
+        });
+    }
+    let before = engine.snapshot();
+    for op in [
+        Operation::Dispute { tx: 1, client: 2 },
+        Operation::Resolve { tx: 1, client: 2 },
+        Operation::Chargeback { tx: 1, client: 2 },
+    ] {
+        assert_eq!(
+            engine.valid(&op),
+            Err(WarningKind::ClientMismatch { tx: 1, client: 2 })
+        );
+        engine.apply(op);
+    }
+    assert_eq!(engine.snapshot(), before);
+    assert!(!engine.is_disputed(1));
+
+    // A mismatch doesn't release a dispute opened by the owner
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    engine.apply(Operation::Chargeback { tx: 1, client: 2 });
+    assert!(engine.is_disputed(1));
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.held, 5.into());
+    assert!(!client.locked);
+    assert_eq!(engine.warning_counts()["client_mismatch"], 4);
+}
+
+#[test]
+fn test_reject_reason_variants() {
+    let mut engine = TransactionEngine::new();
+    let deposit = |tx, client, amount: i32| Operation::Deposit {
+        tx,
+        client,
+        amount: amount.into(),
+    };
+    engine.apply_operation(deposit(1, 1, 5)).unwrap();
+    engine.apply_operation(deposit(2, 2, 5)).unwrap();
+    let cases = vec![
+        (deposit(1, 1, 1), EngineError::TransactionExists(1)),
+        (
+            Operation::Chargeback { tx: 7, client: 1 },
+            EngineError::TransactionNotFound(7),
+        ),
+        (
+            Operation::Withdrawal {
+                tx: 3,
+                client: 1,
+                amount: 6.into(),
+            },
+            EngineError::InsufficientFunds {
+                client: 1,
+                requested: 6.into(),
+                available: 5.into(),
+            },
+        ),
+        (
+            Operation::Dispute { tx: 1, client: 2 },
+            EngineError::ClientMismatch { tx: 1, client: 2 },
+        ),
+        (
+            deposit(4, 1, -1),
+            EngineError::NegativeAmount(Amount::from_str("-1").unwrap()),
+        ),
+    ];
+    for (op, reason) in cases {
+        assert_eq!(engine.apply_operation(op), Err(reason));
+    }
+    // The mismatched dispute didn't mark the transaction disputed
+    engine
+        .apply_operation(Operation::Dispute { tx: 1, client: 1 })
+        .unwrap();
+    assert_eq!(
+        engine.apply_operation(Operation::Dispute { tx: 1, client: 1 }),
+        Err(EngineError::AlreadyDisputed(1))
+    );
+    engine
+        .apply_operation(Operation::Chargeback { tx: 1, client: 1 })
+        .unwrap();
+    let err = engine.apply_operation(deposit(5, 1, 1)).unwrap_err();
+    assert_eq!(err, EngineError::ClientLocked(1));
+    let err: Box<dyn std::error::Error> = Box::new(err);
+    assert_eq!(err.to_string(), "Client 1 locked");
+}
+
+#[test]
+fn test_rejection_logged_as_warning() {
+    // Captures records logged by the current test's thread only
+    testing_logger::setup();
+    let mut engine = TransactionEngine::new();
+    engine.apply(Operation::Withdrawal {
+        tx: 2,
+        client: 1,
+        amount: 1.into(),
+    });
+    testing_logger::validate(|logs| {
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].level, log::Level::Warn);
+        assert_eq!(logs[0].body, "No such client: 1");
+    });
+}
+
+#[test]
+fn test_warning_seq() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.0
+dispute,1,2,
+withdrawal,1,3,2.0
+bogus,1,4,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let seqs: Vec<u64> = engine.warnings().iter().map(|w| w.seq).collect();
+    assert_eq!(seqs, vec![0, 1, 2]);
+
+    engine.drain_warnings();
+    engine.apply(Operation::Resolve { tx: 1, client: 1 });
+    engine.apply(Operation::Chargeback { tx: 1, client: 1 });
+    let seqs: Vec<u64> = engine.warnings().iter().map(|w| w.seq).collect();
+    assert_eq!(seqs, vec![3, 4]);
+}
+
+#[test]
+fn test_check_balanced() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+deposit,2,4,2.5
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert_eq!(engine.check_balanced(15.into()), Ok(()));
+
+    // The matching deposit of the withdrawal is missing from the feed
+    let input = r#"type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert_eq!(
+        engine.check_balanced(15.into()),
+        Err(Amount::from_str("12.5").unwrap())
+    );
+}
+
+#[test]
+fn test_snapshot_delta() {
+    let mut engine = TransactionEngine::new().with_change_tracking();
+    let base_input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+deposit,3,3,4.0
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(base_input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    let base = engine.snapshot();
+
+    let input = r#"type,client,tx,amount
+deposit,1,4,1.0
+dispute,2,2,
+withdrawal,3,5,10.0
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    let delta = engine.snapshot_delta_since(base.seq);
+    // Client 3 was touched by a rejected withdrawal, but didn't change
+    let changed: Vec<ClientId> = delta.clients.iter().map(|(id, _)| *id).collect();
+    assert_eq!(changed, vec![1, 2, 3]);
+    let changed: Vec<TxId> = delta.transactions.iter().map(|(tx, _)| *tx).collect();
+    assert_eq!(changed, vec![2, 4]);
+
+    let mut restored = TransactionEngine::new();
+    restored.apply_delta(&base);
+    restored.apply_delta(&delta);
+    assert_eq!(restored.snapshot(), engine.snapshot());
+    assert!(restored.is_disputed(2));
+}
+
+#[test]
+fn test_round_trip_check() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,5.1234
+withdrawal,1,3,12
+dispute,1,2,
+deposit,2,4,3
+withdrawal,2,5,1
+dispute,2,5,
+deposit,3,6,4
+dispute,3,6,
+chargeback,3,6,
+deposit,4,7,2
+void,4,7,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert_eq!(engine.check_round_trip(), Ok(()));
+    assert_ne!(engine.state_hash(), TransactionEngine::new().state_hash());
+}
+
+#[test]
+fn test_operation_log_replay() {
+    let input = r#"type,client,tx,amount,note
+deposit,1,1,10
+deposit,1,1,10
+withdrawal,1,2,25
+withdrawal,1,3,4
+dispute,1,9,
+dispute,1,1,
+resolve,1,1,
+deposit,2,4,3
+adjustment,2,5,1,"bonus, manual"
+dispute,2,4,
+chargeback,2,4,
+deposit,2,6,1
+"#;
+    let path = std::env::temp_dir().join(format!(
+        "transactions-operation-log-{}.csv",
+        std::process::id()
+    ));
+    let log = File::create(&path).unwrap();
+    let mut engine = TransactionEngine::new().with_operation_log(log, true);
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    engine.finish_operation_log().unwrap();
+    assert_eq!(engine.warnings().len(), 4);
+
+    let content = fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        content.lines().collect::<Vec<_>>(),
+        vec![
+            "type,client,tx,amount,note,target",
+            "deposit,1,1,10,,",
+            "withdrawal,1,3,4,,",
+            "dispute,1,1,,,",
+            "resolve,1,1,,,",
+            "deposit,2,4,3,,",
+            "adjustment,2,5,1,\"bonus, manual\",",
+            "dispute,2,4,,,",
+            "chargeback,2,4,,,",
+        ]
+    );
+
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(content.as_bytes());
+    let replayed = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert!(replayed.warnings().is_empty());
+    assert_eq!(replayed.state_hash(), engine.state_hash());
+    assert_eq!(
+        replayed.adjustments()[&5].note,
+        engine.adjustments()[&5].note
+    );
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_void() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+void,1,2,
+dispute,1,2,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 5.into());
+    assert_eq!(client.held, 0.into());
+    assert!(!client.locked);
+    assert!(!engine.is_disputed(2));
+    assert_eq!(
+        engine.warnings().last().unwrap().kind,
+        WarningKind::TransactionVoided(2)
+    );
+    assert!(engine.inconsistent_totals().is_empty());
+}
+
+#[test]
+fn test_refund() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+refund,2,2,
+refund,1,2,
+dispute,1,2,
+void,1,2,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 5.into());
+    assert_eq!(client.held, 0.into());
+    assert!(!client.locked);
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::ClientMismatch { tx: 2, client: 2 },
+            WarningKind::TransactionRefunded(2),
+            WarningKind::TransactionRefunded(2),
+        ]
+    );
+    let mut journal = Vec::new();
+    engine.write_journal(&mut journal).unwrap();
+    let journal = String::from_utf8(journal).unwrap();
+    assert!(journal.trim_end().ends_with(",refunded,,"), "{}", journal);
+    assert!(engine.inconsistent_totals().is_empty());
+}
+
+#[test]
+fn test_refund_overdraw() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,3.0
+refund,1,1,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 2.into());
+    assert_eq!(
+        engine.warnings().last().unwrap().kind,
+        WarningKind::InsufficientFunds {
+            client: 1,
+            requested: 5.into(),
+            available: 2.into(),
+        }
+    );
+}
+
+#[test]
+fn test_transfer() {
+    let input = r#"type,client,tx,amount,target
+deposit,1,1,10
+transfer,1,2,4,2
+transfer,1,3,7,2
+transfer,1,4,1,
+transfer,1,5,1,1
+deposit,3,6,5
+dispute,3,6,
+chargeback,3,6,
+transfer,3,7,1,1
+transfer,1,8,1,3
+dispute,2,2,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let clients = engine.clients();
+    assert_eq!(clients[&1].available, 6.into());
+    assert_eq!(clients[&2].available, 0.into());
+    assert_eq!(clients[&2].held, 4.into());
+    assert!(engine.is_disputed(2));
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::InsufficientFunds {
+                client: 1,
+                requested: 7.into(),
+                available: 6.into(),
+            },
+            WarningKind::MissingTarget(4),
+            WarningKind::SelfTransfer(5),
+            WarningKind::ClientLocked(3),
+            WarningKind::ClientLocked(3),
+        ]
+    );
+    assert!(engine.verify_integrity().is_ok());
+}
+
+#[test]
+fn test_amend() {
+    let first = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+withdrawal,1,3,6.0
+deposit,2,4,1.0
+"#;
+    let second = r#"type,client,tx,amount
+deposit,1,1,7.0
+amend,1,1,7.0
+amend,1,2,0.5
+amend,1,2,1.0
+amend,1,3,1.0
+amend,2,1,1.0
+dispute,2,4,
+amend,2,4,2.0
+"#;
+    let mut engine = TransactionEngine::new();
+    for input in [first, second] {
+        let reader = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes());
+        engine.apply_csv_reader(reader).unwrap();
+    }
+    // 5 + 3 - 6, then +2 from the amended deposit 1 and -2 from deposit 2,
+    // of which only 2 wasn't drawn by the withdrawal
+    let client = &engine.clients()[&1];
+    assert_eq!(client.available, 2.into());
+    assert_eq!(client.held, 0.into());
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::TransactionExists(1),
+            WarningKind::InsufficientFunds {
+                client: 1,
+                requested: Amount::from_str("2.5").unwrap(),
+                available: 2.into(),
+            },
+            WarningKind::NotADeposit(3),
+            WarningKind::ClientMismatch { tx: 1, client: 2 },
+            WarningKind::AlreadyDisputed(4),
+        ]
+    );
+    assert!(engine.verify_integrity().is_ok());
+}
+
+#[test]
+fn test_zero_tx_id_rejection() {
+    let input = r#"type,client,tx,amount
+deposit,1,0,5
+"#;
+    for reject in [false, true] {
+        let reader = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes());
+        let mut engine = TransactionEngine::new().with_zero_tx_id_rejection(reject);
+        engine.apply_csv_reader(reader).unwrap();
+        if reject {
+            assert!(engine.clients().is_empty());
+            assert_eq!(engine.warnings()[0].kind, WarningKind::ZeroTxId);
+        } else {
+            assert_eq!(engine.clients()[&1].available, 5.into());
+            assert!(engine.warnings().is_empty());
+        }
+    }
+}
+
+#[test]
+fn test_tx_id_recycling() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+dispute,1,1,
+chargeback,1,1,
+deposit,2,2,3
+deposit,2,1,-4
+withdrawal,2,1,10
+"#;
+    let more = r#"type,client,tx,amount
+deposit,2,1,4
+deposit,2,2,1
+"#;
+    for recycle in [false, true] {
+        let mut engine = TransactionEngine::new().with_tx_id_recycling(recycle);
+        let reader = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes());
+        engine.apply_csv_reader(reader).unwrap();
+        // Rejected rows don't recycle the id
+        assert_eq!(engine.transactions[&1].client, 1);
+        assert!(engine.charged_back.contains(&1));
+        let reader = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(more.as_bytes());
+        engine.apply_csv_reader(reader).unwrap();
+        let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+        let client = engine.clients().get(&2).unwrap();
+        if recycle {
+            assert_eq!(
+                kinds,
+                vec![
+                    WarningKind::NegativeAmount(Amount::from(-4)),
+                    WarningKind::InsufficientFunds {
+                        client: 2,
+                        requested: 10.into(),
+                        available: 3.into(),
+                    },
+                    WarningKind::TransactionExists(2)
+                ]
+            );
+            assert_eq!(client.available, 7.into());
+            assert_eq!(engine.transactions[&1].client, 2);
+            assert!(!engine.charged_back.contains(&1));
+        } else {
+            assert_eq!(
+                kinds,
+                vec![
+                    WarningKind::TransactionExists(1),
+                    WarningKind::TransactionExists(1),
+                    WarningKind::TransactionExists(1),
+                    WarningKind::TransactionExists(2)
+                ]
+            );
+            assert_eq!(client.available, 3.into());
+        }
+        assert!(engine.inconsistent_totals().is_empty());
+    }
+}
+
+#[test]
+fn test_void_withdrawn_deposit() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,1.0
+void,2,1,
+void,1,2,
+void,1,1,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 4.into());
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::ClientMismatch { tx: 1, client: 2 },
+            WarningKind::NotADeposit(2),
+            WarningKind::InsufficientFunds {
+                client: 1,
+                requested: 5.into(),
+                available: 4.into(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_header_aliases() {
+    let input = r#"txn_type,customer,reference,value
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,2.0
+dispute,2,2,
+"#;
+    let aliases = [
+        ("txn_type", "type"),
+        ("customer", "client"),
+        ("reference", "tx"),
+        ("value", "amount"),
+    ]
+    .iter()
+    .map(|(from, to)| (from.to_string(), to.to_string()))
+    .collect();
+    let mut engine = TransactionEngine::new().with_header_aliases(aliases);
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    assert!(engine.warnings().is_empty());
+    let clients = engine.clients();
+    assert_eq!(clients.get(&1).unwrap().available, 3.into());
+    assert_eq!(clients.get(&2).unwrap().available, 0.into());
+    assert_eq!(clients.get(&2).unwrap().held, 3.into());
+}
+
+#[test]
+fn test_disputes_disabled() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,2.0
+dispute,1,1,
+resolve,1,1,
+chargeback,1,1,
+void,1,1,
+"#;
+    let mut engine = TransactionEngine::new().with_disputes_enabled(false);
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    assert!(engine.transactions.is_empty());
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 3.into());
+    assert_eq!(client.held, 0.into());
+    assert!(!client.locked);
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, vec![WarningKind::DisputesDisabled(1); 4]);
+}
+
+#[test]
+fn test_streaming() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,1,2,3.0
+withdrawal,1,3,1.0
+dispute,1,3,
+dispute,1,1,
+resolve,1,1,
+"#;
+    let mut engine = TransactionEngine::new().with_streaming();
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    // Only the deposit which can still be disputed is kept
+    assert_eq!(engine.transactions.len(), 1);
+    assert!(engine.transactions.contains_key(&2));
+    assert!(engine.draws.is_empty());
+    assert!(engine.dispute_amounts.is_empty());
+
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    engine.apply(Operation::Dispute { tx: 2, client: 1 });
+    engine.apply(Operation::Chargeback { tx: 2, client: 1 });
+    assert!(engine.transactions.is_empty());
+    assert!(engine.charged_back.is_empty());
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 4.into());
+    assert_eq!(client.held, 0.into());
+    assert!(client.locked);
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::TransactionNotFound(3),
+            WarningKind::TransactionNotFound(1),
+        ]
+    );
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_from_csv_parallel() {
+    // Rows of each client in order, interleaved pseudo-randomly across clients
+    let mut per_client: Vec<Vec<String>> = Vec::new();
+    let mut tx = 0;
+    for client in 1..=8 {
+        let mut rows = Vec::new();
+        for i in 0..20 {
+            tx += 1;
+            rows.push(format!("deposit,{},{},{}.5", client, tx, i + 1));
+            if i % 3 == 0 {
+                rows.push(format!("withdrawal,{},{},{}", client, tx + 1000, i + 2));
+            }
+            if i % 5 == 0 {
+                rows.push(format!("dispute,{},{},", client, tx));
+            }
+            if i % 10 == 0 {
+                rows.push(format!("resolve,{},{},", client, tx));
+            }
+            // Transfers connect clients 2 and 3, and 5 and 6
+            if i == 12 && client % 3 == 0 {
+                let target = client - 1;
+                rows.push(format!("transfer,{},{},7,{}", client, tx + 2000, target));
+            }
+        }
+        if client % 4 == 0 {
+            rows.push(format!("chargeback,{},{},", client, tx - 4));
+        }
+        rows.reverse();
+        per_client.push(rows);
+    }
+    let mut input = String::from("type,client,tx,amount,target\n");
+    let mut seed: u64 = 7;
+    while per_client.iter().any(|rows| !rows.is_empty()) {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        let client = (seed >> 33) as usize % per_client.len();
+        if let Some(row) = per_client[client].pop() {
+            input += &row;
+            input.push('\n');
+        }
+    }
+    let reader = || {
+        ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes())
+    };
+    let serial = TransactionEngine::from_csv_reader(reader()).unwrap();
+    let parallel = TransactionEngine::from_csv_parallel(reader()).unwrap();
+    assert_eq!(parallel.clients(), serial.clients());
+    assert!(serial.clients()[&4].locked);
+    assert_eq!(serial.transfer_sources.len(), 2);
+    assert_eq!(
+        parallel.snapshot().transactions,
+        serial.snapshot().transactions
+    );
+    assert_eq!(parallel.warnings(), serial.warnings());
+    assert_eq!(parallel.rows_read(), serial.rows_read());
+}
+
+#[test]
+fn test_top_clients() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,5.0
+deposit,2,3,5.0
+withdrawal,2,4,1.0
+dispute,2,2,
+deposit,3,5,1.0
+deposit,3,6,1.0
+withdrawal,1,7,100.0
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert_eq!(engine.top_clients(2), vec![(2, 4), (3, 2)]);
+    assert_eq!(engine.top_clients(10), vec![(2, 4), (3, 2), (1, 1)]);
+}
+
+#[test]
+fn test_signed_amounts() {
+    let input = r#"type,client,tx,amount
+transaction,1,1,5
+transaction,1,2,-2
+transaction,2,3,-1
+dispute,1,2,
+transaction,1,4,-6
+withdrawal,1,5,-1
+"#;
+    let mut engine = TransactionEngine::new().with_signed_amounts();
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    let clients = engine.clients();
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 5.into());
+    assert_eq!(client.held, (-2).into());
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::ClientNotFound(2),
+            WarningKind::InsufficientFunds {
+                client: 1,
+                requested: 6.into(),
+                available: 5.into(),
+            },
+            WarningKind::NegativeAmount((-1).into()),
+        ]
+    );
+}
+
+#[test]
+fn test_signed_amounts_disabled() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+transaction,1,2,3
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.get(&1).unwrap().available, 5.into());
+}
+
+#[test]
+fn test_journal_order() {
+    let input = r#"type,client,tx,amount
+deposit,1,30,5
+deposit,2,4,3
+withdrawal,1,7,1
+deposit,1,12,1
+deposit,2,100,1
+dispute,2,4,
+dispute,1,12,
+chargeback,1,12,
+void,2,100,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let mut journal = Vec::new();
+    engine.write_journal(&mut journal).unwrap();
+    assert_eq!(
+        String::from_utf8(journal).unwrap(),
+        r#"type,client,tx,amount,state,note,target
+deposit,2,4,3,disputed,,
+withdrawal,1,7,1,,,
+deposit,1,12,1,charged_back,,
+deposit,1,30,5,,,
+deposit,2,100,1,voided,,
+"#
+    );
+}
+
+#[test]
+fn test_resolve_cooldown() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+dispute,1,1,
+resolve,1,1,
+dispute,1,1,
+dispute,1,1,
+deposit,1,2,1
+dispute,1,1,
+"#;
+    let mut engine = TransactionEngine::new().with_resolve_cooldown(2);
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::ResolveCooldown {
+                tx: 1,
+                remaining: 2
+            },
+            WarningKind::ResolveCooldown {
+                tx: 1,
+                remaining: 1
+            },
+        ]
+    );
+    assert!(engine.is_disputed(1));
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 1.into());
+    assert_eq!(client.held, 5.into());
+}
+
+#[cfg(feature = "row-hash")]
+#[test]
+fn test_row_hash() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new().with_row_hash_key(b"key");
+    engine.apply_csv_reader(reader).unwrap();
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked,hash\n\
+         1,5,0,5,false,caebfec9559fd54336bfadc001a3b2ab5d16d41386e236aaae58d0b407100014\n"
+    );
+}
+
+#[test]
+fn test_dispute_withdrawn_deposit() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,8
+dispute,1,1,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 0.into());
+    assert_eq!(client.held, 2.into());
+    assert!(engine.warnings().is_empty());
+}
+
+#[test]
+fn test_dispute_drawn_down_deposits() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,5
+withdrawal,1,3,12
+dispute,1,1,
+dispute,1,2,
+chargeback,1,2,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 0.into());
+    assert_eq!(client.held, 0.into());
+    assert!(client.locked);
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, vec![WarningKind::FullyWithdrawn(1)]);
+    assert!(engine.inconsistent_totals().is_empty());
+}
+
+#[test]
+fn test_serialize_engine() {
+    let input = r#"type,client,external_id,tx,amount
+deposit,1,,1,5.1234
+deposit,,acme,2,3
+withdrawal,1,,3,1.5
+dispute,1,,1,
+deposit,2,,4,2
+dispute,2,,4,
+chargeback,2,,4,
+resolve,3,,5,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new()
+        .with_change_tracking()
+        .with_resolve_cooldown(1);
+    engine.apply_csv_reader(reader).unwrap();
+    let json = serde_json::to_string(&engine).unwrap();
+    // Amounts are strings, so that no precision is lost
+    assert!(json.contains(r#""5.1234""#));
+    let deserialized: TransactionEngine = serde_json::from_str(&json).unwrap();
+    assert_eq!(deserialized, engine);
+}
+
+#[test]
+fn test_precision_warnings() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1.123456
+deposit,1,2,1.1200
+"#;
+    let mut engine = TransactionEngine::new().with_precision_warnings();
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    engine.apply_csv_reader(reader).unwrap();
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![WarningKind::ExcessivePrecision { tx: 1, scale: 6 }]
+    );
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, Amount::from_str("2.2435").unwrap());
+}
+
+#[test]
+fn test_resume() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,5
+withdrawal,1,3,12
+deposit,2,4,3
+dispute,2,4,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let mut balances = Vec::new();
+    engine.to_csv_writer(&mut balances).unwrap();
+    let mut journal = Vec::new();
+    engine.write_journal(&mut journal).unwrap();
+
+    let mut resumed = TransactionEngine::new();
+    resumed
+        .resume(
+            ReaderBuilder::new().from_reader(balances.as_slice()),
+            ReaderBuilder::new().from_reader(journal.as_slice()),
+        )
+        .unwrap();
+    let (snapshot, expected) = (resumed.snapshot(), engine.snapshot());
+    assert_eq!(snapshot.clients, expected.clients);
+    assert_eq!(snapshot.transactions, expected.transactions);
+
+    let input = r#"type,client,tx,amount
+dispute,1,2,
+resolve,2,4,
+dispute,1,1,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    resumed.apply_csv_reader(reader).unwrap();
+    let clients = resumed.clients();
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, 0.into());
+    assert_eq!(client.held, 3.into());
+    assert_eq!(clients.get(&2).unwrap().available, 3.into());
+    let kinds: Vec<WarningKind> = resumed.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, vec![WarningKind::FullyWithdrawn(1)]);
+}
+
+// Resumes a new engine from the balances and journal written by the given one
+#[cfg(test)]
+fn resumed(engine: &TransactionEngine) -> TransactionEngine {
+    let mut balances = Vec::new();
+    engine.to_csv_writer(&mut balances).unwrap();
+    let mut journal = Vec::new();
+    engine.write_journal(&mut journal).unwrap();
+    let mut resumed = TransactionEngine::new();
+    resumed
+        .resume(
+            ReaderBuilder::new().from_reader(balances.as_slice()),
+            ReaderBuilder::new().from_reader(journal.as_slice()),
+        )
+        .unwrap();
+    resumed
+}
+
+#[test]
+fn test_resume_clamped_dust() {
+    let mut engine = TransactionEngine::new();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: Amount::from_str("0.0001").unwrap(),
+    });
+    engine.clamp_dust(1.into());
+    let mut resumed = resumed(&engine);
+    assert!(resumed.verify_integrity().is_ok());
+
+    // Applying CSV input checks the totals in debug builds
+    let input = "type,client,tx,amount\ndeposit,1,2,1.0\n";
+    resumed
+        .apply_csv_reader(ReaderBuilder::new().from_reader(input.as_bytes()))
+        .unwrap();
+    assert_eq!(resumed.clients().get(&1).unwrap().available, 1.into());
+    assert!(resumed.verify_integrity().is_ok());
+}
+
+#[test]
+fn test_resume_adjustments() {
+    let mut engine = TransactionEngine::new();
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 5.into(),
+    });
+    engine
+        .apply_adjustment(2, 1, 3.into(), "goodwill, ticket 42")
+        .unwrap();
+    engine.apply_adjustment(3, 1, (-1).into(), "fee").unwrap();
+    let mut resumed = resumed(&engine);
+    assert_eq!(resumed.adjustments(), engine.adjustments());
+    // The journal accounts for the whole balance
+    assert!(resumed.seeded.is_empty());
+
+    let input = "type,client,tx,amount\ndeposit,1,4,1.0\ndeposit,1,2,1.0\n";
+    resumed
+        .apply_csv_reader(ReaderBuilder::new().from_reader(input.as_bytes()))
+        .unwrap();
+    assert_eq!(resumed.clients().get(&1).unwrap().available, 8.into());
+    let kinds: Vec<WarningKind> = resumed.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, vec![WarningKind::TransactionExists(2)]);
+    assert!(resumed.verify_integrity().is_ok());
+}
+
+#[test]
+fn test_resume_transfers() {
+    let input = r#"type,client,tx,amount,target
+deposit,1,1,5
+transfer,1,2,3,2
+deposit,3,3,4
+transfer,3,4,1,1
+dispute,1,4,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let mut journal = Vec::new();
+    engine.write_journal(&mut journal).unwrap();
+    let journal = String::from_utf8(journal).unwrap();
+    assert!(journal.contains("\ntransfer,1,2,3,,,2\n"), "{}", journal);
+    let mut resumed = resumed(&engine);
+    assert_eq!(resumed.transfer_sources, engine.transfer_sources);
+    assert_eq!(
+        resumed.snapshot().transactions,
+        engine.snapshot().transactions
+    );
+    assert!(resumed.seeded.is_empty());
+
+    // Only 2 of the source's deposit weren't transferred
+    let input = r#"type,client,tx,amount
+void,2,2,
+dispute,1,1,
+resolve,1,4,
+deposit,2,5,1
+"#;
+    resumed
+        .apply_csv_reader(ReaderBuilder::new().from_reader(input.as_bytes()))
+        .unwrap();
+    let kinds: Vec<WarningKind> = resumed.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, vec![WarningKind::NotADeposit(2)]);
+    let clients = resumed.clients();
+    assert_eq!(clients[&1].held, 2.into());
+    assert_eq!(clients[&1].available, 1.into());
+    assert_eq!(clients[&2].available, 4.into());
+    assert!(resumed.verify_integrity().is_ok());
+}
+
+#[cfg(not(feature = "minor-units-core"))]
+#[test]
+fn test_deposit_overflow() {
+    let mut engine = TransactionEngine::new();
+    let near_max = Decimal::MAX - Decimal::from(10);
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: near_max,
+    });
+    engine.apply(Operation::Deposit {
+        tx: 2,
+        client: 1,
+        amount: near_max,
+    });
+    // Fits in available funds, but not in total funds once the first is disputed
+    engine.apply(Operation::Dispute { tx: 1, client: 1 });
+    engine.apply(Operation::Deposit {
+        tx: 3,
+        client: 1,
+        amount: 11.into(),
+    });
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            WarningKind::Overflow { tx: 2, client: 1 },
+            WarningKind::Overflow { tx: 3, client: 1 },
+        ]
+    );
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 0.into());
+    assert_eq!(client.held, near_max);
+    assert_eq!(engine.transactions.len(), 1);
+    assert!(engine.verify_integrity().is_ok());
+}
+
+#[test]
+fn test_client_info_with_held_locked() {
+    let info = ClientInfo::with_held_locked(3.into(), 2.into(), true);
+    assert_eq!(info.available(), 3.into());
+    assert_eq!(info.held(), 2.into());
+    assert_eq!(info.total(), 5.into());
+    assert!(info.locked());
+    assert_eq!(info.lock_reason(), None);
+
+    let snapshot = Snapshot {
+        seq: 1,
+        clients: vec![(1, info.clone())],
+        transactions: Vec::new(),
+        adjustments: Vec::new(),
+    };
+    let mut engine = TransactionEngine::new();
+    engine.apply_delta(&snapshot);
+    // No transaction is recorded for the loaded balances
+    assert!(engine.transactions.is_empty());
+    assert_eq!(engine.clients().get(&1), Some(&info));
+    engine.apply(Operation::Deposit {
+        tx: 1,
+        client: 1,
+        amount: 1.into(),
+    });
+    assert_eq!(engine.clients().get(&1), Some(&info));
+    assert_eq!(engine.warning_counts()["client_locked"], 1);
+}
+
+#[cfg(not(feature = "minor-units-core"))]
+#[test]
+fn test_total_overflow() {
+    // Can't be reached by applying operations, only by loading a corrupt state
+    let near_max = Decimal::MAX - Decimal::from(10);
+    let info = ClientInfo {
+        available: near_max,
+        held: near_max,
+        locked: false,
+        lock_reason: None,
+    };
+    assert_eq!(info.total(), Decimal::MAX);
+    let snapshot = Snapshot {
+        seq: 1,
+        clients: vec![(1, info), (2, ClientInfo::new(1.into()))],
+        transactions: Vec::new(),
+        adjustments: Vec::new(),
+    };
+    let mut engine = TransactionEngine::new();
+    engine.apply_delta(&snapshot);
+
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(
+        output.lines().nth(1).unwrap(),
+        format!("1,{},{},{},false", near_max, near_max, Decimal::MAX)
+    );
+    engine.to_json(Vec::new()).unwrap();
+    assert_eq!(engine.grand_total(), Decimal::MAX);
+    assert_eq!(engine.check_balanced(0.into()), Err(Decimal::MAX));
+}
+
+#[cfg(not(feature = "minor-units-core"))]
+#[test]
+fn test_checked_sum() {
+    let max = Decimal::MAX;
+    assert_eq!(checked_sum(Vec::new()), Some(0.into()));
+    assert_eq!(checked_sum(vec![max, max, -max]), Some(max));
+    assert_eq!(
+        checked_sum(vec![max, -max, max, 1.into(), -max]),
+        Some(1.into())
+    );
+    assert_eq!(checked_sum(vec![max, 1.into()]), None);
+    assert_eq!(checked_sum(vec![-max, -max, max, -Decimal::from(1)]), None);
+}
+
+#[cfg(not(feature = "minor-units-core"))]
+#[test]
+fn test_precision_loss() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,7000000000000000000000000.0001
+deposit,1,2,1.5
+deposit,1,3,1000000000000000000000000.0001
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(kinds, vec![WarningKind::PrecisionLoss { tx: 3, client: 1 }]);
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(
+        client.available,
+        Amount::from_str("8000000000000000000000001.500").unwrap()
+    );
+}
+
+#[test]
+fn test_reverse_order() {
+    let process = |input: &str, engine: TransactionEngine| {
+        let mut engine = engine;
+        let reader = ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(input.as_bytes());
+        engine.apply_csv_reader(reader).unwrap();
+        engine
+    };
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+deposit,2,2,3
+deposit,1,3,1.5
+"#;
+    let forward = process(input, TransactionEngine::new());
+    let reverse = process(input, TransactionEngine::new().with_reverse_order());
+    assert_eq!(forward.clients(), reverse.clients());
+    assert!(reverse.warnings().is_empty());
+
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+dispute,1,1,
+"#;
+    let forward = process(input, TransactionEngine::new());
+    let reverse = process(input, TransactionEngine::new().with_reverse_order());
+    assert!(forward.warnings().is_empty());
+    assert_eq!(
+        reverse.warnings(),
+        &[Warning {
+            seq: 0,
+            line: Some(3),
+            kind: WarningKind::TransactionNotFound(1),
+        }]
+    );
+    assert_eq!(reverse.clients().get(&1).unwrap().available, 5.into());
+}
+
+#[test]
+fn test_rejected_withdrawal_id_reused() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1
+withdrawal,1,5,3
+deposit,1,5,2
+dispute,1,5,
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let kinds: Vec<WarningKind> = engine.warnings().iter().map(|w| w.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![WarningKind::InsufficientFunds {
+            client: 1,
+            requested: 3.into(),
+            available: 1.into(),
+        }]
+    );
+    assert!(engine.is_disputed(5));
+    let client = engine.clients().get(&1).unwrap();
+    assert_eq!(client.available, 1.into());
+    assert_eq!(client.held, 2.into());
+}
+
+#[test]
+fn test_daily_volumes() {
+    let input = r#"type,client,tx,amount,timestamp
+deposit,1,1,5,1699920100
+deposit,2,2,3,1699970000
+withdrawal,1,3,1.5,1699980000
+withdrawal,2,4,10,1699990000
+deposit,1,5,2,1700006400
+withdrawal,2,6,1,1700006401
+deposit,1,7,4,
+dispute,1,1,,1700006402
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new().with_volume_buckets(BucketSize::Daily);
+    engine.apply_csv_reader(reader).unwrap();
+    let mut output = Vec::new();
+    engine.write_volumes(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "bucket,deposits,withdrawals\n1699920000,8,1.5\n1700006400,2,1\n"
+    );
+}
+
+#[cfg(not(feature = "minor-units-core"))]
+#[test]
+fn test_volumes_saturate() {
+    let input = r#"type,client,tx,amount,timestamp
+deposit,1,1,50000000000000000000000000000,1699920100
+deposit,2,2,50000000000000000000000000000,1699970000
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new().with_volume_buckets(BucketSize::Daily);
+    engine.apply_csv_reader(reader).unwrap();
+    assert!(engine.warnings().is_empty());
+    let mut output = Vec::new();
+    engine.write_volumes(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        format!(
+            "bucket,deposits,withdrawals\n1699920000,{},0\n",
+            Decimal::MAX
+        )
+    );
+}
+
+#[test]
+fn test_withdraw_deposit() {
+    let input = r#"type,client,tx,amount
+withdrawal,1,1,5.01
+deposit,1,2,7.01
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, Amount::from_str("7.01").unwrap());
+    assert_eq!(client.held, 0.into());
+    assert!(!client.locked);
+}
+
+#[test]
+fn test_dispute_not_applied() {
+    let input = r#"type,client,tx,amount
+withdrawal,1,1,5.01
+deposit,1,2,7.01
+dispute,1,1,
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, Amount::from_str("7.01").unwrap());
+    assert_eq!(client.held, 0.into());
+    assert!(!client.locked);
+}
+
+#[test]
+fn test_dispute_not_applied_client_exists() {
+    let input = r#"type,client,tx,amount
+deposit,1,4,3.0013
+withdrawal,1,1,5.01
+deposit,1,2,7.01
+dispute,1,1,
+"#;
+    let clients = test_clients(input);
+    assert_eq!(clients.len(), 1);
+    let client = clients.get(&1).unwrap();
+    assert_eq!(client.available, Amount::from_str("10.0113").unwrap());
+    assert_eq!(client.held, 0.into());
+    assert!(!client.locked);
+}
+
+#[cfg(test)]
+fn four_places_decimal() -> impl Strategy<Value = Decimal> {
+    (
+        -1_000_000_000_000_000i64..1_000_000_000_000_000,
+        0..=DECIMAL_PLACES,
+    )
+        .prop_map(|(num, scale)| Decimal::new(num, scale))
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn test_minor_units_agree_with_decimal(a in four_places_decimal(), b in four_places_decimal()) {
+        let x = MinorUnits::from_decimal(a).unwrap();
+        let y = MinorUnits::from_decimal(b).unwrap();
+        prop_assert_eq!(x.to_decimal(), a);
+        prop_assert_eq!((x + y).to_decimal(), a + b);
+        prop_assert_eq!((x - y).to_decimal(), a - b);
+        prop_assert_eq!(x.cmp(&y), a.cmp(&b));
+        prop_assert_eq!(x.to_string(), a.normalize().to_string());
+    }
+}
+
+#[test]
+fn test_minor_units_precision() {
+    let exact = MinorUnits::from_str("5.12340").unwrap();
+    assert_eq!(exact, MinorUnits(51234));
+    assert_eq!(exact.to_string(), "5.1234");
+    assert!(MinorUnits::from_str("5.12345").is_err());
+    assert_eq!(MinorUnits::from(-3).to_string(), "-3");
+    assert_eq!(MinorUnits::from_str("-0.05").unwrap().to_string(), "-0.05");
+}
+
+#[test]
+fn test_header_policy() {
+    let mut output = Vec::new();
+    let engine = TransactionEngine::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    assert_eq!(output, b"client,available,held,total,locked\n");
+
+    let mut output = Vec::new();
+    let engine = TransactionEngine::new().with_header_policy(HeaderPolicy::WhenNonEmpty);
+    engine.to_csv_writer(&mut output).unwrap();
+    assert!(output.is_empty());
+}
+
+#[test]
+fn test_external_ids() {
+    let input = r#"type,external_id,tx,amount
+deposit,2b5e7f0c-0d4a-4e5e-9f4b-6f1e0c0b7a11,1,5
+deposit,9d0a4c3e-7b1f-4a7e-8c2d-3e4f5a6b7c8d,2,3
+withdrawal,2b5e7f0c-0d4a-4e5e-9f4b-6f1e0c0b7a11,3,1
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let clients = engine.clients();
+    assert_eq!(clients.len(), 2);
+    assert_eq!(clients.get(&1).unwrap().available, 4.into());
+    assert_eq!(clients.get(&2).unwrap().available, 3.into());
+
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let mut lines: Vec<&str> = output.lines().collect();
+    lines[1..].sort_unstable();
+    assert_eq!(
+        lines,
+        vec![
+            "client,external_id,available,held,total,locked",
+            "1,2b5e7f0c-0d4a-4e5e-9f4b-6f1e0c0b7a11,4,0,4,false",
+            "2,9d0a4c3e-7b1f-4a7e-8c2d-3e4f5a6b7c8d,3,0,3,false",
+        ]
+    );
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_zstd_input() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,1.3
+dispute,2,2,
+"#;
+    let compressed = zstd::encode_all(input.as_bytes(), 0).unwrap();
+    let mut engine = TransactionEngine::new();
+    engine.apply_zstd_csv(compressed.as_slice()).unwrap();
+    let clients = engine.clients();
+    let expected = test_clients(input);
+    assert_eq!(clients.len(), expected.len());
+    for (id, info) in clients.iter() {
+        let expected = expected.get(&id).unwrap();
+        assert_eq!(info.available, expected.available);
+        assert_eq!(info.held, expected.held);
+    }
+
+    let mut corrupt = compressed.clone();
+    corrupt.truncate(compressed.len() / 2);
+    corrupt.extend_from_slice(b"garbage");
+    let mut engine = TransactionEngine::new();
+    let result = engine.apply_zstd_csv(corrupt.as_slice());
+    assert!(matches!(result, Err(EngineError::Io(_))));
+
+    let mut engine = TransactionEngine::new();
+    let result = engine.apply_zstd_csv(&b"not zstd at all"[..]);
+    assert!(matches!(result, Err(EngineError::Io(_))));
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn test_gzip_input() {
+    use flate2::write::GzEncoder;
+
+    let input = r#"type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,1.3
+dispute,2,2,
+deposit,2,2,1.0
+"#;
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(input.as_bytes()).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let mut engine = TransactionEngine::new();
+    engine.apply_gzip_csv(compressed.as_slice()).unwrap();
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let expected = TransactionEngine::from_csv_reader(reader).unwrap();
+    assert_eq!(engine.snapshot(), expected.snapshot());
+    assert_eq!(engine.warnings(), expected.warnings());
+
+    let path = std::env::temp_dir().join(format!("transactions-{}.csv.gz", std::process::id()));
+    fs::write(&path, &compressed).unwrap();
+    let engine = TransactionEngine::from_csv(path.to_str().unwrap()).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(engine.snapshot(), expected.snapshot());
+
+    let mut engine = TransactionEngine::new();
+    let result = engine.apply_gzip_csv(&b"not gzip at all"[..]);
+    assert!(matches!(result, Err(EngineError::Io(_))));
+}
+
+#[test]
+fn test_output_flushed() {
+    let mut engine = TransactionEngine::new();
+    for client in 1..=1000 {
+        engine.apply(Operation::Deposit {
+            tx: client as TxId,
+            client,
+            amount: 1.into(),
+        });
+    }
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.ends_with("1,0,1,false\n"));
+    let mut lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 1001);
+    lines[1..].sort_unstable();
+    lines.dedup();
+    assert_eq!(lines.len(), 1001);
+}
+
+#[test]
+fn test_output_fields() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,5
+deposit,2,2,3
+dispute,2,2,
+chargeback,2,2,
+"#;
+    let fields = "total,client,locked"
+        .split(',')
+        .map(OutputField::from_str)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let mut engine = TransactionEngine::new().with_output_fields(fields);
+    engine.apply_csv_reader(reader).unwrap();
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let mut lines: Vec<&str> = output.lines().collect();
+    lines[1..].sort_unstable();
+    assert_eq!(lines, vec!["total,client,locked", "0,2,true", "5,1,false"]);
+    assert_eq!(
+        OutputField::from_str("balance"),
+        Err("Unknown output field balance".to_string())
+    );
+}
+
+#[test]
+fn test_external_id_quoting() {
+    let input = r#"type,external_id,tx,amount
+deposit,"Doe, John",1,5
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let mut output = Vec::new();
+    engine.to_csv_writer(&mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,external_id,available,held,total,locked\n1,\"Doe, John\",5,0,5,false\n"
+    );
+}
+
+#[test]
+fn test_shard_output() {
+    let input = r#"type,client,tx,amount
+deposit,1,1,1
+deposit,2,2,2
+deposit,3,3,3
+deposit,4,4,4
+"#;
+    let reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(input.as_bytes());
+    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
+    let dir = std::env::temp_dir().join(format!("transactions-shards-{}", std::process::id()));
+    engine.to_sharded_csv(&dir, 2).unwrap();
+
+    let shard_rows = |shard: u16| {
+        let content = fs::read_to_string(dir.join(format!("shard-{}.csv", shard))).unwrap();
+        let mut lines: Vec<String> = content.lines().map(String::from).collect();
+        lines[1..].sort();
+        lines
+    };
+    assert_eq!(
+        shard_rows(0),
+        vec![
+            "client,available,held,total,locked",
+            "2,2,0,2,false",
+            "4,4,0,4,false"
+        ]
+    );
+    assert_eq!(
+        shard_rows(1),
+        vec![
+            "client,available,held,total,locked",
+            "1,1,0,1,false",
+            "3,3,0,3,false"
+        ]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
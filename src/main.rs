@@ -1,596 +1,969 @@
-#[cfg(test)]
-use core::str::FromStr;
-use csv::{Error, ReaderBuilder, Trim};
-use rust_decimal::Decimal;
-use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::env;
-
-type ClientId = u16;
-type TxId = u32;
-
-// Client information consists of their available and held funds
-// and information whether the client is locked.
-// Total funds are not stored, since they can be trivially calculated
-// from available + held.
-#[derive(Debug, Clone)]
-struct ClientInfo {
-    available: Decimal,
-    held: Decimal,
-    locked: bool,
-}
-
-impl ClientInfo {
-    fn new(amount: Decimal) -> Self {
-        Self {
-            available: amount,
-            held: Decimal::new(0, 0),
-            locked: false,
-        }
-    }
-
-    fn total(&self) -> Decimal {
-        self.available + self.held
-    }
-}
-
-// There's no specific type associated with deposit or withdrawal,
-// because it only introduces complications in the type system and no gains:
-// the transaction simply uses positive/negative amounts
-struct Transaction {
-    client: ClientId,
-    amount: Decimal,
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use transactions::{
+    Amount, BucketSize, ClientId, DisputePolicy, DisputeScope, DisputeSource, EngineError,
+    HeaderPolicy, OutputField, Snapshot, TransactionEngine,
+};
+
+const USAGE: &str = "[COMMAND] ...
+
+Commands:
+    [process] [OPTIONS] [path-to-csv...]
+                              process transactions and print balances; the default command
+    snapshot SNAPSHOT [OPTIONS] [path-to-csv...]
+                              process transactions and write the final state into SNAPSHOT
+    verify SNAPSHOT           check that balances in SNAPSHOT match its transactions
+    merge SNAPSHOT...         combine snapshots, later ones taking precedence, and print
+                              the result
+
+Multiple files are processed in order as a single input, e.g. a deposit from one file
+can be disputed in a later one. Transactions are read from stdin if a path is `-` or
+no path is given. Files ending with .gz are decompressed (requires the gzip feature).
+
+Options of process and snapshot:
+    --output FILE             write balances into FILE instead of stdout
+    --no-clobber              fail instead of overwriting an existing --output FILE
+    --format FORMAT           balances output format: csv | json (default: csv)
+    --shard-output DIR        write balances into DIR/shard-<n>.csv files
+    --shards N                number of shards for --shard-output (default: 1)
+    --header-policy POLICY    always | when-non-empty (default: always)
+    --input-buffer-size BYTES size of the buffer input is read through
+    --ndjson                  input is newline-delimited JSON, one object with the CSV columns
+                              as fields per line
+    --no-header               input has no header row; columns are type, client, tx, amount
+    --dispute-source SOURCE   funds disputes have to fit in: available | total (default: available)
+    --lock-after-redisputes N lock clients after N attempts to dispute their already disputed
+                              transactions
+    --forbid-adjustments      reject `adjustment` rows
+    --dense-clients           keep clients in a flat array indexed by client id, which is
+                              faster for client ids densely covering the u16 range
+    --streaming               keep only deposits which can still be disputed in memory;
+                              withdrawals can't be disputed in this mode
+    --dispute-policy POLICY   disputable transactions: all | deposits-only (default: all)
+    --dispute-scope SCOPE     transactions disputes can refer to: global | per-file, where
+                              per-file rejects disputes of transactions from other files
+                              (default: global)
+    --strict-dispute-amounts  reject resolves and chargebacks not moving exactly the amount
+                              held by the dispute
+    --held-invariant          reject disputes leaving held funds negative or above total funds
+    --fixed-point             print balances as integers in 1/10000 units after a `# scale=4` line
+    --lock-reason             append a lock_reason column, e.g. chargeback:5 or admin
+    --client ID               process and print only client ID, can be repeated
+    --output-fields FIELDS    comma-separated output columns out of client, external_id,
+                              available, held, total, locked, lock_reason and hash
+    --limit N                 process only the first N rows read
+    --sample-rate RATE        process only a reproducible pseudo-random fraction RATE of rows,
+                              between 0 and 1; approximate, e.g. disputes of skipped deposits
+                              are rejected, so only meant for quick analytics
+    --sample-seed SEED        seed choosing rows for --sample-rate (default: 0)
+    --anonymize SEED          replace client ids in balances with pseudonyms, the same for
+                              the same SEED, and leave external ids out
+    --anonymize-map PATH      write the client id behind each pseudonym into PATH
+    --assert-balanced TOTAL   fail unless the sum of all clients' total funds equals TOTAL
+    --clamp-dust THRESHOLD    zero out balances smaller than THRESHOLD in absolute value
+    --max-decimal-places-warning
+                              warn about amounts with more than 4 decimal places
+                              and apply them rounded
+    --reverse                 apply rows newest first; disputes of earlier rows are rejected
+    --recycle-tx-ids          accept ids of voided or charged back transactions for new ones
+    --reject-zero-tx          reject deposits and withdrawals with transaction id 0
+    --signed-amounts          treat `transaction` rows as deposits or withdrawals depending on the sign
+    --dump-journal PATH       write stored transactions and adjustments sorted by id into PATH
+    --disputes-report PATH    write all disputes with their amounts and outcomes into PATH
+    --operation-log PATH      append every applied operation to PATH as it's applied, as CSV
+                              which can be processed again to reproduce the same state
+    --resume BALANCES JOURNAL continue from balances and a journal written by a prior run
+    --round-trip-check        fail unless the final state survives a snapshot round trip unchanged
+    --volumes SIZE PATH       write deposit and withdrawal volumes per hourly or daily bucket
+                              of the timestamp column into PATH
+    --benchmark               print the number of rows processed, the time it took and
+                              throughput to stderr
+    --trace-client ID         print balances of client ID after each operation applied to it
+                              to stderr
+    --profile K               print K clients with the most applied operations to stderr
+    --zstd                    input is zstd-compressed, implied by the .zst extension
+                              (requires the zstd feature)
+    --row-hash KEY            append an HMAC-SHA256 of each output row keyed with KEY
+                              (requires the row-hash feature)";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Csv,
+    Json,
 }
 
-#[derive(Default)]
-struct TransactionEngine {
-    // Performance note: if the number of clients is expected to be close to u16::MAX,
-    // it's better to use a flat array of clients, which is more cache-friendly
-    // and allows much faster lookups. This optimization should only be applied
-    // if looking up clients shows up as a bottleneck in microbenchmarks
-    clients: HashMap<ClientId, ClientInfo>,
-    transactions: HashMap<TxId, Transaction>,
-    disputed: HashSet<TxId>,
+// Path argument standing for stdin
+const STDIN_PATH: &str = "-";
+
+// Command line arguments
+#[derive(Debug)]
+struct Args {
+    paths: Vec<String>,
+    output: Option<PathBuf>,
+    no_clobber: bool,
+    format: OutputFormat,
+    shard_output: Option<PathBuf>,
+    shards: u16,
+    header_policy: HeaderPolicy,
+    dispute_source: DisputeSource,
+    dispute_policy: DisputePolicy,
+    dispute_scope: DisputeScope,
+    held_invariant: bool,
+    streaming: bool,
+    dense_clients: bool,
+    strict_dispute_amounts: bool,
+    forbid_adjustments: bool,
+    redispute_limit: Option<u32>,
+    no_header: bool,
+    ndjson: bool,
+    input_buffer_size: Option<usize>,
+    fixed_point: bool,
+    lock_reason: bool,
+    output_fields: Option<Vec<OutputField>>,
+    limit: Option<usize>,
+    sample_rate: Option<f64>,
+    sample_seed: u64,
+    anonymize_seed: Option<u64>,
+    anonymize_map: Option<PathBuf>,
+    clients: HashSet<ClientId>,
+    clamp_dust: Option<Amount>,
+    expected_total: Option<Amount>,
+    profile: Option<usize>,
+    trace_client: Option<ClientId>,
+    benchmark: bool,
+    journal: Option<PathBuf>,
+    disputes_report: Option<PathBuf>,
+    operation_log: Option<PathBuf>,
+    volumes: Option<(BucketSize, PathBuf)>,
+    resume: Option<(String, String)>,
+    signed_amounts: bool,
+    reverse: bool,
+    recycle_tx_ids: bool,
+    reject_zero_tx: bool,
+    round_trip_check: bool,
+    precision_warnings: bool,
+    #[cfg(feature = "zstd")]
+    zstd: bool,
+    #[cfg(feature = "row-hash")]
+    row_hash_key: Option<String>,
 }
 
-// Transaction engine capable of serving deposits, withdrawals, disputes,
-// resolves and chargebacks
-impl TransactionEngine {
-    fn new() -> Self {
-        Default::default()
-    }
-
-    // Performance note: this validation uses extra lookups in the transactions and
-    // client maps, but also makes the code clearer to read. In case of a bottleneck,
-    // all checks can be inlined to respective functions.
-    fn valid(&self, op: &str, tx: TxId, client: ClientId, amount: Decimal) -> bool {
-        let should_exist: bool = !matches!(op, "deposit" | "withdrawal");
-        let exist_check = match self.transactions.get(&tx) {
-            Some(_) => {
-                if !should_exist {
-                    eprintln!("Transaction {} already exists", tx)
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut paths = Vec::new();
+        let mut output = None;
+        let mut no_clobber = false;
+        let mut format = OutputFormat::Csv;
+        let mut shard_output = None;
+        let mut shards = 1;
+        let mut header_policy = HeaderPolicy::Always;
+        let mut dispute_source = DisputeSource::AvailableOnly;
+        let mut dispute_policy = DisputePolicy::AllowWithdrawals;
+        let mut dispute_scope = DisputeScope::Global;
+        let mut held_invariant = false;
+        let mut streaming = false;
+        let mut dense_clients = false;
+        let mut strict_dispute_amounts = false;
+        let mut forbid_adjustments = false;
+        let mut redispute_limit = None;
+        let mut no_header = false;
+        let mut ndjson = false;
+        let mut input_buffer_size = None;
+        let mut fixed_point = false;
+        let mut lock_reason = false;
+        let mut output_fields = None;
+        let mut limit = None;
+        let mut sample_rate = None;
+        let mut sample_seed = 0;
+        let mut anonymize_seed = None;
+        let mut anonymize_map = None;
+        let mut clients = HashSet::new();
+        let mut clamp_dust = None;
+        let mut expected_total = None;
+        let mut profile = None;
+        let mut trace_client = None;
+        let mut benchmark = false;
+        let mut journal = None;
+        let mut disputes_report = None;
+        let mut operation_log = None;
+        let mut volumes = None;
+        let mut resume = None;
+        let mut signed_amounts = false;
+        let mut reverse = false;
+        let mut recycle_tx_ids = false;
+        let mut reject_zero_tx = false;
+        let mut round_trip_check = false;
+        let mut precision_warnings = false;
+        #[cfg(feature = "zstd")]
+        let mut zstd = false;
+        #[cfg(feature = "row-hash")]
+        let mut row_hash_key = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--output" => {
+                    let file = args.next().ok_or("--output requires a path")?;
+                    output = Some(PathBuf::from(file));
                 }
-                should_exist
-            }
-            None => {
-                if should_exist {
-                    eprintln!("Transaction {} does not exist", tx)
+                "--no-clobber" => no_clobber = true,
+                "--format" => {
+                    format = match args.next().as_deref() {
+                        Some("csv") => OutputFormat::Csv,
+                        Some("json") => OutputFormat::Json,
+                        _ => return Err("--format requires csv or json".into()),
+                    };
+                }
+                "--shard-output" => {
+                    let dir = args.next().ok_or("--shard-output requires a directory")?;
+                    shard_output = Some(PathBuf::from(dir));
+                }
+                "--shards" => {
+                    shards = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or("--shards requires a positive number")?;
+                }
+                "--header-policy" => {
+                    header_policy = match args.next().as_deref() {
+                        Some("always") => HeaderPolicy::Always,
+                        Some("when-non-empty") => HeaderPolicy::WhenNonEmpty,
+                        _ => return Err("--header-policy requires always or when-non-empty".into()),
+                    };
+                }
+                "--dispute-source" => {
+                    dispute_source = match args.next().as_deref() {
+                        Some("available") => DisputeSource::AvailableOnly,
+                        Some("total") => DisputeSource::TotalFunds,
+                        _ => return Err("--dispute-source requires available or total".into()),
+                    };
+                }
+                "--dispute-policy" => {
+                    dispute_policy = match args.next().as_deref() {
+                        Some("all") => DisputePolicy::AllowWithdrawals,
+                        Some("deposits-only") => DisputePolicy::DepositsOnly,
+                        _ => return Err("--dispute-policy requires all or deposits-only".into()),
+                    };
+                }
+                "--dispute-scope" => {
+                    dispute_scope = match args.next().as_deref() {
+                        Some("global") => DisputeScope::Global,
+                        Some("per-file") => DisputeScope::PerFile,
+                        _ => return Err("--dispute-scope requires global or per-file".into()),
+                    };
+                }
+                "--held-invariant" => held_invariant = true,
+                "--streaming" => streaming = true,
+                "--dense-clients" => dense_clients = true,
+                "--strict-dispute-amounts" => strict_dispute_amounts = true,
+                "--forbid-adjustments" => forbid_adjustments = true,
+                "--lock-after-redisputes" => {
+                    let n = args.next().and_then(|n| n.parse().ok());
+                    redispute_limit = Some(n.ok_or("--lock-after-redisputes requires a number")?);
+                }
+                "--no-header" => no_header = true,
+                "--ndjson" => ndjson = true,
+                "--input-buffer-size" => {
+                    let bytes = args.next().and_then(|b| b.parse().ok());
+                    let bytes = bytes.filter(|&b| b > 0);
+                    input_buffer_size =
+                        Some(bytes.ok_or("--input-buffer-size requires a positive number")?);
+                }
+                "--fixed-point" => fixed_point = true,
+                "--lock-reason" => lock_reason = true,
+                "--output-fields" => {
+                    let fields = args
+                        .next()
+                        .ok_or("--output-fields requires a list of fields")?;
+                    let fields = fields
+                        .split(',')
+                        .map(OutputField::from_str)
+                        .collect::<Result<_, _>>()?;
+                    output_fields = Some(fields);
+                }
+                "--limit" => {
+                    let n = args.next().and_then(|n| n.parse().ok());
+                    limit = Some(n.ok_or("--limit requires a number")?);
+                }
+                "--sample-rate" => {
+                    let rate = args.next().and_then(|r| r.parse().ok());
+                    let rate = rate.filter(|r| (0.0..=1.0).contains(r));
+                    sample_rate =
+                        Some(rate.ok_or("--sample-rate requires a number between 0 and 1")?);
+                }
+                "--sample-seed" => {
+                    let seed = args.next().and_then(|s| s.parse().ok());
+                    sample_seed = seed.ok_or("--sample-seed requires a number")?;
+                }
+                "--anonymize" => {
+                    let seed = args.next().and_then(|s| s.parse().ok());
+                    anonymize_seed = Some(seed.ok_or("--anonymize requires a seed")?);
+                }
+                "--anonymize-map" => {
+                    let path = args.next().ok_or("--anonymize-map requires a path")?;
+                    anonymize_map = Some(PathBuf::from(path));
+                }
+                "--client" => {
+                    let id = args.next().and_then(|id| id.parse().ok());
+                    clients.insert(id.ok_or("--client requires a client id")?);
+                }
+                "--assert-balanced" => {
+                    let total = args.next().and_then(|t| Amount::from_str(&t).ok());
+                    expected_total = Some(total.ok_or("--assert-balanced requires an amount")?);
+                }
+                "--clamp-dust" => {
+                    let threshold = args.next().and_then(|t| Amount::from_str(&t).ok());
+                    clamp_dust = Some(threshold.ok_or("--clamp-dust requires an amount")?);
+                }
+                "--profile" => {
+                    let k = args.next().and_then(|k| k.parse().ok());
+                    profile = Some(k.ok_or("--profile requires a number")?);
+                }
+                "--trace-client" => {
+                    let id = args.next().and_then(|id| id.parse().ok());
+                    trace_client = Some(id.ok_or("--trace-client requires a client id")?);
+                }
+                "--benchmark" => benchmark = true,
+                "--dump-journal" => {
+                    let path = args.next().ok_or("--dump-journal requires a path")?;
+                    journal = Some(PathBuf::from(path));
+                }
+                "--disputes-report" => {
+                    let path = args.next().ok_or("--disputes-report requires a path")?;
+                    disputes_report = Some(PathBuf::from(path));
+                }
+                "--operation-log" => {
+                    let path = args.next().ok_or("--operation-log requires a path")?;
+                    operation_log = Some(PathBuf::from(path));
+                }
+                "--volumes" => {
+                    let bucket_size = match args.next().as_deref() {
+                        Some("hourly") => BucketSize::Hourly,
+                        Some("daily") => BucketSize::Daily,
+                        _ => return Err("--volumes requires hourly or daily".into()),
+                    };
+                    let path = args.next().ok_or("--volumes requires a path")?;
+                    volumes = Some((bucket_size, PathBuf::from(path)));
+                }
+                "--resume" => {
+                    let balances = args.next();
+                    let journal = args.next();
+                    resume = Some(
+                        balances
+                            .zip(journal)
+                            .ok_or("--resume requires balances and journal paths")?,
+                    );
                 }
-                !should_exist
+                "--signed-amounts" => signed_amounts = true,
+                "--reverse" => reverse = true,
+                "--recycle-tx-ids" => recycle_tx_ids = true,
+                "--reject-zero-tx" => reject_zero_tx = true,
+                "--round-trip-check" => round_trip_check = true,
+                "--max-decimal-places-warning" => precision_warnings = true,
+                #[cfg(feature = "zstd")]
+                "--zstd" => zstd = true,
+                #[cfg(feature = "row-hash")]
+                "--row-hash" => {
+                    row_hash_key = Some(args.next().ok_or("--row-hash requires a key")?);
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unexpected argument: {}", arg)),
+                _ => paths.push(arg),
             }
-        };
-        if !exist_check {
-            return false;
         }
-        if amount.is_sign_negative() {
-            eprintln!("Invalid negative amount for deposit: {}", amount);
-            return false;
+        if output.is_some() && shard_output.is_some() {
+            return Err("--output and --shard-output are mutually exclusive".into());
         }
-        if let Some(info) = self.clients.get(&client) {
-            if info.locked {
-                eprintln!("Client {} locked", client);
-                return false;
-            }
+        if format == OutputFormat::Json && shard_output.is_some() {
+            return Err("--shard-output supports only the csv format".into());
         }
-        true
-    }
-
-    // Deposits funds
-    fn deposit(&mut self, tx: TxId, client: ClientId, amount: Decimal) {
-        self.transactions.insert(tx, Transaction { client, amount });
-
-        if let Some(info) = self.clients.get_mut(&client) {
-            info.available += amount;
-        } else {
-            self.clients.insert(client, ClientInfo::new(amount));
+        if no_clobber && output.is_none() {
+            return Err("--no-clobber requires --output".into());
+        }
+        if anonymize_map.is_some() && anonymize_seed.is_none() {
+            return Err("--anonymize-map requires --anonymize".into());
         }
+        Ok(Self {
+            paths: if paths.is_empty() {
+                vec![STDIN_PATH.to_string()]
+            } else {
+                paths
+            },
+            output,
+            no_clobber,
+            format,
+            shard_output,
+            shards,
+            header_policy,
+            dispute_source,
+            dispute_policy,
+            dispute_scope,
+            held_invariant,
+            streaming,
+            dense_clients,
+            strict_dispute_amounts,
+            forbid_adjustments,
+            redispute_limit,
+            no_header,
+            ndjson,
+            input_buffer_size,
+            fixed_point,
+            lock_reason,
+            output_fields,
+            limit,
+            sample_rate,
+            sample_seed,
+            anonymize_seed,
+            anonymize_map,
+            clients,
+            clamp_dust,
+            expected_total,
+            profile,
+            trace_client,
+            benchmark,
+            journal,
+            disputes_report,
+            operation_log,
+            volumes,
+            resume,
+            signed_amounts,
+            reverse,
+            recycle_tx_ids,
+            reject_zero_tx,
+            round_trip_check,
+            precision_warnings,
+            #[cfg(feature = "zstd")]
+            zstd,
+            #[cfg(feature = "row-hash")]
+            row_hash_key,
+        })
     }
 
-    // Withdraws funds if possible; the operation is ignored if no sufficient
-    // funds are available
-    fn withdraw(&mut self, tx: TxId, client: ClientId, amount: Decimal) {
-        let mut amount = amount;
-        amount.set_sign_negative(true);
-        self.transactions.insert(tx, Transaction { client, amount });
-
-        if let Some(info) = self.clients.get_mut(&client) {
-            if info.available + amount >= 0.into() {
-                info.available += amount;
+    // Applies a single input file, or stdin
+    fn apply_input(&self, engine: &mut TransactionEngine, path: &str) -> Result<(), EngineError> {
+        let stdin = path == STDIN_PATH;
+        if self.ndjson {
+            return if stdin {
+                engine.apply_ndjson(io::stdin().lock())
             } else {
-                eprintln!(
-                    "Not enough funds {} for withdrawing {}",
-                    info.available, amount
-                );
-                self.transactions.remove(&tx);
-            }
-        } else {
-            eprintln!("No such client: {}", client);
-            self.transactions.remove(&tx);
+                engine.apply_ndjson(File::open(path)?)
+            };
         }
+        #[cfg(feature = "zstd")]
+        let processed = match (self.zstd, stdin) {
+            (true, true) => engine.apply_zstd_csv(io::stdin().lock()),
+            (true, false) => engine.apply_zstd_csv_path(path),
+            (false, true) => engine.apply_csv(io::stdin().lock()),
+            (false, false) => engine.apply_csv_path(path),
+        };
+        #[cfg(not(feature = "zstd"))]
+        let processed = if stdin {
+            engine.apply_csv(io::stdin().lock())
+        } else {
+            engine.apply_csv_path(path)
+        };
+        processed
     }
 
-    // Handles a dispute, moving funds into `held`
-    // NOTE: disputing a withdrawal is not specified, so the semantics
-    // are assumed as follows: it's legal to dispute, resolve and chargeback
-    // withdrawals, but available funds may never go below zero, or the operation
-    // is dropped as invalid. Alternatively, withdrawal disputes could be banned,
-    // which is trivial to validate.
-    fn dispute(&mut self, tx: TxId, client: ClientId) {
-        if self.disputed.contains(&tx) {
-            eprintln!("Transaction already disputed");
-            return;
-        }
-        self.disputed.insert(tx);
-        if let Some(tx_entry) = self.transactions.get(&tx) {
-            if tx_entry.client != client {
-                eprintln!(
-                    "Disputed transaction {} doesn't match the client id {}, skipping",
-                    tx, client
-                );
-                return;
-            }
-            if let Some(info) = self.clients.get_mut(&client) {
-                let amount = tx_entry.amount;
-                if amount > info.available {
-                    eprintln!(
-                        "Disputed amount {} larger than available funds: {}, skipping",
-                        amount, info.available
-                    );
-                    return;
-                }
-                info.available -= amount;
-                info.held += amount;
-            }
+    // Creates an engine configured according to the arguments
+    fn engine(&self) -> TransactionEngine {
+        let mut engine = TransactionEngine::new()
+            .with_header_policy(self.header_policy)
+            .with_dispute_source(self.dispute_source)
+            .with_policy(self.dispute_policy)
+            .with_dispute_scope(self.dispute_scope);
+        if let Some(client) = self.trace_client {
+            engine = engine.with_client_trace(client);
         }
-    }
-
-    // Resolves a dispute, moving funds from `held` back into `available`
-    fn resolve(&mut self, tx: TxId, client: ClientId) {
-        if !self.disputed.contains(&tx) {
-            eprintln!("Transaction not disputed");
-            return;
-        }
-        self.disputed.remove(&tx);
-
-        if let Some(tx_entry) = self.transactions.get(&tx) {
-            if tx_entry.client != client {
-                eprintln!(
-                    "Resolved transaction {} doesn't match the client id {}, skipping",
-                    tx, client
-                );
-                return;
-            }
-            if let Some(info) = self.clients.get_mut(&client) {
-                let amount = tx_entry.amount;
-                if amount > info.held {
-                    eprintln!(
-                        "Resolved amount {} larger than held funds: {}, skipping",
-                        amount, info.held
-                    );
-                    return;
-                }
-                info.available += amount;
-                info.held -= amount;
-            }
+        if let Some(attempts) = self.redispute_limit {
+            engine = engine.with_redispute_lock(attempts);
         }
-    }
-
-    // Charges back a dispute, removing funds from `held` and locking the account
-    fn chargeback(&mut self, tx: TxId, client: ClientId) {
-        if !self.disputed.contains(&tx) {
-            eprintln!("Transaction not disputed");
-            return;
-        }
-        self.disputed.remove(&tx);
-
-        if let Some(tx_entry) = self.transactions.get(&tx) {
-            if tx_entry.client != client {
-                eprintln!(
-                    "Charged-back transaction {} doesn't match the client id {}, skipping",
-                    tx, client
-                );
-                return;
-            }
-            if let Some(info) = self.clients.get_mut(&client) {
-                let amount = tx_entry.amount;
-                if amount > info.held {
-                    eprintln!(
-                        "Charged-back amount {} larger than held funds: {}, skipping",
-                        amount, info.held
-                    );
-                    return;
-                }
-                info.held -= amount;
-                info.locked = true;
-            }
+        if self.forbid_adjustments {
+            engine = engine.with_adjustments_forbidden();
         }
-    }
-
-    fn from_csv_reader<R: std::io::Read>(mut reader: csv::Reader<R>) -> Result<Self, Box<Error>> {
-        let mut engine = Self::new();
-
-        for row in reader.deserialize::<Row>() {
-            match row {
-                Ok(row) => {
-                    let amount = row.amount.unwrap_or_else(|| 0.into());
-                    if !engine.valid(&row.op, row.tx, row.client, amount) {
-                        continue;
-                    }
-                    match row.op.as_str() {
-                        "deposit" => engine.deposit(row.tx, row.client, amount),
-                        "withdrawal" => engine.withdraw(row.tx, row.client, amount),
-                        "dispute" => engine.dispute(row.tx, row.client),
-                        "resolve" => engine.resolve(row.tx, row.client),
-                        "chargeback" => engine.chargeback(row.tx, row.client),
-                        _ => eprintln!("Unknown transaction type {}", row.op),
-                    }
-                }
-                Err(e) => eprintln!("Invalid row: {}", e),
-            }
+        if self.held_invariant {
+            engine = engine.with_held_invariant();
+        }
+        if self.streaming {
+            engine = engine.with_streaming();
+        }
+        if self.dense_clients {
+            engine = engine.with_dense_client_store();
         }
-        Ok(engine)
+        if self.strict_dispute_amounts {
+            engine = engine.with_strict_dispute_amounts();
+        }
+        if self.no_header {
+            engine = engine.with_no_header();
+        }
+        if let Some(bytes) = self.input_buffer_size {
+            engine = engine.with_input_buffer_size(bytes);
+        }
+        if self.fixed_point {
+            engine = engine.with_fixed_point_output();
+        }
+        if self.lock_reason {
+            engine = engine.with_lock_reason_column();
+        }
+        if let Some(fields) = &self.output_fields {
+            engine = engine.with_output_fields(fields.clone());
+        }
+        if let Some((bucket_size, _)) = self.volumes {
+            engine = engine.with_volume_buckets(bucket_size);
+        }
+        if self.disputes_report.is_some() {
+            engine = engine.with_dispute_tracking();
+        }
+        if let Some(limit) = self.limit {
+            engine = engine.with_row_limit(limit);
+        }
+        if let Some(rate) = self.sample_rate {
+            engine = engine.with_sample_rate(rate, self.sample_seed);
+        }
+        if let Some(seed) = self.anonymize_seed {
+            engine = engine.with_anonymized_output(seed);
+        }
+        if !self.clients.is_empty() {
+            engine = engine.with_client_filter(self.clients.clone());
+        }
+        if self.signed_amounts {
+            engine = engine.with_signed_amounts();
+        }
+        if self.recycle_tx_ids {
+            engine = engine.with_tx_id_recycling(true);
+        }
+        if self.reject_zero_tx {
+            engine = engine.with_zero_tx_id_rejection(true);
+        }
+        if self.reverse {
+            engine = engine.with_reverse_order();
+        }
+        if self.precision_warnings {
+            engine = engine.with_precision_warnings();
+        }
+        #[cfg(feature = "row-hash")]
+        if let Some(key) = &self.row_hash_key {
+            engine = engine.with_row_hash_key(key.as_bytes());
+        }
+        engine
     }
+}
 
-    fn from_csv(path: &str) -> Result<Self, Box<Error>> {
-        let reader = ReaderBuilder::new()
-            .trim(Trim::All)
-            .flexible(true)
-            .from_path(path)?;
-
-        Self::from_csv_reader(reader)
+// Creates an engine according to the arguments and applies the input on top
+// of the resumed state, if any
+fn load(args: &Args) -> Result<TransactionEngine, String> {
+    let mut engine = args.engine();
+    if let Some(path) = &args.operation_log {
+        engine = open_operation_log(engine, path)?;
     }
-
-    fn to_csv(&self) {
-        println!("client,available,held,total,locked");
-        self.clients
-            .iter()
-            .map(|(id, info)| {
-                println!(
-                    "{},{},{},{},{}",
-                    id,
-                    info.available,
-                    info.held,
-                    info.total(),
-                    info.locked
-                )
-            })
-            .collect()
+    if let Some((balances, journal)) = &args.resume {
+        engine
+            .resume_from_paths(balances, journal)
+            .map_err(|e| format!("Failed to resume: {}", e))?;
     }
-
-    #[cfg(test)]
-    fn clients(&self) -> &HashMap<ClientId, ClientInfo> {
-        &self.clients
+    let start = Instant::now();
+    for path in &args.paths {
+        args.apply_input(&mut engine, path)
+            .map_err(|e| format!("Failed to process {}: {}", path, e))?;
     }
+    if args.benchmark {
+        eprintln!("{}", throughput(engine.rows_read(), start.elapsed()));
+    }
+    engine
+        .finish_operation_log()
+        .map_err(|e| format!("Failed to write operation log: {}", e))?;
+    Ok(engine)
 }
 
-#[derive(Debug, Deserialize)]
-struct Row {
-    #[serde(rename = "type")]
-    op: String,
-    client: ClientId,
-    tx: TxId,
-    amount: Option<Decimal>,
+// Appends to the operation log, starting it with a header if it's new or empty
+fn open_operation_log(engine: TransactionEngine, path: &Path) -> Result<TransactionEngine, String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open operation log {}: {}", path.display(), e))?;
+    let empty = file.metadata().map_or(true, |metadata| metadata.len() == 0);
+    Ok(engine.with_operation_log(file, empty))
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} path-to-csv", &args[0]);
-        std::process::exit(1);
-    }
-    let path = &args[1];
+fn throughput(rows: u64, elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    let rate = if seconds > 0.0 {
+        rows as f64 / seconds
+    } else {
+        0.0
+    };
+    format!(
+        "Processed {} rows in {:.3}s, {:.0} rows/s",
+        rows, seconds, rate
+    )
+}
 
-    match TransactionEngine::from_csv(&path) {
-        Ok(engine) => engine.to_csv(),
-        Err(e) => {
-            eprintln!("{}", e);
-            std::process::exit(1)
-        }
-    }
+// Output is written to a temporary file next to the --output file, so that it
+// can be renamed over it once complete
+fn temp_output(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
 }
 
-#[cfg(test)]
-fn test_clients(input: &str) -> HashMap<ClientId, ClientInfo> {
-    let reader = ReaderBuilder::new()
-        .flexible(true)
-        .from_reader(input.as_bytes());
-    let engine = TransactionEngine::from_csv_reader(reader).unwrap();
-    let clients = engine.clients();
-    clients.clone()
+fn output_error(path: &Path, e: io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::AlreadyExists => format!("Output file {} already exists", path.display()),
+        _ => format!("Failed to write output file {}: {}", path.display(), e),
+    }
 }
 
-#[test]
-fn test_deposit_duplicated() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,1.0
-deposit,1,1,1.0
-deposit,2,1,1.0
-deposit,3,1,1.0
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, 1.into());
-    assert_eq!(client.locked, false);
+// Creates the temporary file for --output, refusing to overwrite an existing
+// one with --no-clobber
+fn open_output(path: &Path, no_clobber: bool) -> Result<(PathBuf, File), String> {
+    if no_clobber && path.exists() {
+        return Err(output_error(path, io::ErrorKind::AlreadyExists.into()));
+    }
+    let temp = temp_output(path);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .map(|file| (temp, file))
+        .map_err(|e| output_error(path, e))
 }
 
-#[test]
-fn test_withdraw() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,5.0
-withdrawal,1,2,7.0
-withdrawal,1,3,5.1
-withdrawal,1,4,5.5
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, 5.into());
-    assert_eq!(client.locked, false);
+// Moves the complete output in place. With --no-clobber it's hard linked
+// instead, which fails rather than replacing a file created in the meantime
+fn commit_output(temp: &Path, path: &Path, no_clobber: bool) -> Result<(), String> {
+    let moved = if no_clobber {
+        std::fs::hard_link(temp, path).and_then(|()| std::fs::remove_file(temp))
+    } else {
+        std::fs::rename(temp, path)
+    };
+    moved.map_err(|e| output_error(path, e))
 }
 
-#[test]
-fn test_dispute_unresolved() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,5.0
-deposit,1,2,3.0
-deposit,1,3,4.0
-deposit,1,4,1.0
-dispute,1,4,
-dispute,1,2,
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, 9.into());
-    assert_eq!(client.held, 4.into());
-    assert_eq!(client.locked, false);
+fn process(args: &Args) -> Result<(), String> {
+    // Opened upfront so that an existing file is reported before processing
+    let (temp, output) = match &args.output {
+        Some(path) => {
+            let (temp, file) = open_output(path, args.no_clobber)?;
+            (Some(temp), Some(file))
+        }
+        None => (None, None),
+    };
+    let mut result = process_into(args, output);
+    if let (Some(temp), Some(path)) = (&temp, &args.output) {
+        result = result.and_then(|()| commit_output(temp, path, args.no_clobber));
+        // Only the temporary file is removed, so that incomplete output is
+        // neither mistaken for the result nor replaces a previous one
+        if result.is_err() {
+            let _ = std::fs::remove_file(temp);
+        }
+    }
+    result
 }
 
-#[test]
-fn test_dispute_resolve() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,5.0
-deposit,1,2,3.0
-deposit,1,3,4.0
-deposit,1,4,1.0
-dispute,1,4,
-dispute,1,2,
-resolve,1,4,
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, 10.into());
-    assert_eq!(client.held, 3.into());
-    assert_eq!(client.locked, false);
+fn process_into(args: &Args, output: Option<File>) -> Result<(), String> {
+    let mut engine = load(args)?;
+    if let Some(threshold) = args.clamp_dust {
+        engine.clamp_dust(threshold);
+    }
+    if let Some(expected) = args.expected_total {
+        engine.check_balanced(expected).map_err(|total| {
+            format!(
+                "Grand total {} doesn't match the expected {}",
+                total, expected
+            )
+        })?;
+    }
+    if args.round_trip_check {
+        engine
+            .check_round_trip()
+            .map_err(|e| format!("Round trip check failed: {}", e))?;
+    }
+    if args.trace_client.is_some() {
+        engine
+            .write_trace(io::stderr().lock())
+            .map_err(|e| format!("Failed to write trace: {}", e))?;
+    }
+    if let Some(k) = args.profile {
+        for (client, count) in engine.top_clients(k) {
+            eprintln!("Client {}: {} operations", client, count);
+        }
+    }
+    if let Some(path) = &args.journal {
+        File::create(path)
+            .and_then(|file| engine.write_journal(file))
+            .map_err(|e| format!("Failed to write journal: {}", e))?;
+    }
+    if let Some(path) = &args.disputes_report {
+        File::create(path)
+            .and_then(|file| engine.write_disputes_report(file))
+            .map_err(|e| format!("Failed to write disputes report: {}", e))?;
+    }
+    if let Some(path) = &args.anonymize_map {
+        File::create(path)
+            .and_then(|file| engine.write_anonymization_map(file))
+            .map_err(|e| format!("Failed to write anonymization map: {}", e))?;
+    }
+    if let Some((_, path)) = &args.volumes {
+        File::create(path)
+            .and_then(|file| engine.write_volumes(file))
+            .map_err(|e| format!("Failed to write volumes: {}", e))?;
+    }
+    let written = match (&args.shard_output, output, args.format) {
+        (Some(dir), _, _) => engine.to_sharded_csv(dir, args.shards),
+        (None, Some(file), OutputFormat::Csv) => engine.to_csv_writer(file),
+        (None, Some(file), OutputFormat::Json) => engine.to_json(file),
+        (None, None, OutputFormat::Csv) => engine.to_csv(),
+        (None, None, OutputFormat::Json) => engine.to_json(io::stdout().lock()),
+    };
+    written.map_err(|e| format!("Failed to write output: {}", e))
 }
 
-#[test]
-fn test_dispute_chargeback() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,5.0
-deposit,1,2,3.0
-deposit,1,3,4.0
-deposit,1,4,1.0
-dispute,1,4,
-dispute,1,2,
-chargeback,1,4,
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, 9.into());
-    assert_eq!(client.held, 3.into());
-    assert_eq!(client.locked, true);
+fn snapshot(args: &Args, path: &Path) -> Result<(), String> {
+    let engine = load(args)?;
+    File::create(path)
+        .and_then(|file| engine.snapshot().write_json(file))
+        .map_err(|e| format!("Failed to write snapshot: {}", e))
 }
 
-#[test]
-fn test_locked() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,5.0
-deposit,1,2,3.0
-deposit,1,3,4.0
-deposit,1,4,1.0
-dispute,1,4,
-dispute,1,2,
-chargeback,1,4,
-deposit,1,7,100
-deposit,1,8,15
-withdrawal,1,9,7
-dispute,1,9,
-chargeback,1,9
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, 9.into());
-    assert_eq!(client.held, 3.into());
-    assert_eq!(client.locked, true);
+fn read_snapshot(path: &Path) -> Result<Snapshot, String> {
+    File::open(path)
+        .and_then(Snapshot::read_json)
+        .map_err(|e| format!("Failed to read snapshot {}: {}", path.display(), e))
 }
 
-#[test]
-fn test_multiple_clients() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,5.0
-deposit,2,2,3.0
-withdrawal,1,3,1.3
-withdrawal,1,5,1.1
-deposit,3,4,7.0
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 3);
-    let client1 = clients.get(&1).unwrap();
-    let client2 = clients.get(&2).unwrap();
-    let client3 = clients.get(&3).unwrap();
-    assert_eq!(client1.available, Decimal::from_str("2.6").unwrap());
-    assert_eq!(client2.available, 3.into());
-    assert_eq!(client3.available, 7.into());
+fn verify(path: &Path) -> Result<(), String> {
+    let mut engine = TransactionEngine::new();
+    engine.apply_delta(&read_snapshot(path)?);
+    engine.verify_integrity().map_err(|clients| {
+        format!(
+            "Total funds don't match applied transactions for clients {:?}",
+            clients
+        )
+    })
 }
 
-#[test]
-fn test_precision() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,5.1234
-deposit,1,2,1.4321
-withdrawal,1,3,1.1111
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, Decimal::from_str("5.4444").unwrap());
-    assert_eq!(client.held, 0.into());
-    assert_eq!(client.locked, false);
+fn merge<W: Write>(paths: &[PathBuf], writer: W) -> Result<(), String> {
+    let mut engine = TransactionEngine::new();
+    for path in paths {
+        engine.apply_delta(&read_snapshot(path)?);
+    }
+    engine
+        .snapshot()
+        .write_json(writer)
+        .map_err(|e| format!("Failed to write snapshot: {}", e))
 }
 
-#[test]
-fn test_dispute_unresolved_withdrawal() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,5.0
-deposit,1,2,3.0
-deposit,1,3,4.0
-withdrawal,1,4,8.0
-dispute,1,4,
-dispute,1,2,
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, 9.into());
-    assert_eq!(client.held, Decimal::from_str("-5").unwrap());
-    assert_eq!(client.locked, false);
+// Command line, with arguments of each command parsed
+enum Command {
+    Process(Args),
+    Snapshot(PathBuf, Args),
+    Verify(PathBuf),
+    Merge(Vec<PathBuf>),
 }
 
-#[test]
-fn test_dispute_resolve_withdrawal() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,5.0
-deposit,1,2,3.0
-deposit,1,3,4.0
-withdrawal,1,4,8.0
-dispute,1,4,
-dispute,1,2,
-resolve,1,4,
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, 1.into());
-    assert_eq!(client.held, 3.into());
-    assert_eq!(client.locked, false);
+impl Command {
+    // Arguments without a known command name are arguments of `process`
+    fn parse<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut args = args.peekable();
+        match args.peek().map(String::as_str) {
+            Some("process") => Args::parse(args.skip(1)).map(Command::Process),
+            Some("snapshot") => {
+                let path = args.nth(1).ok_or("snapshot requires a snapshot path")?;
+                Ok(Command::Snapshot(PathBuf::from(path), Args::parse(args)?))
+            }
+            Some("verify") => {
+                let path = args.nth(1).ok_or("verify requires a snapshot path")?;
+                if let Some(arg) = args.next() {
+                    return Err(format!("Unexpected argument: {}", arg));
+                }
+                Ok(Command::Verify(PathBuf::from(path)))
+            }
+            Some("merge") => {
+                let paths: Vec<PathBuf> = args.skip(1).map(PathBuf::from).collect();
+                if paths.is_empty() {
+                    return Err("merge requires snapshot paths".into());
+                }
+                Ok(Command::Merge(paths))
+            }
+            _ => Args::parse(args).map(Command::Process),
+        }
+    }
+
+    fn run(&self) -> Result<(), String> {
+        match self {
+            Command::Process(args) => process(args),
+            Command::Snapshot(path, args) => snapshot(args, path),
+            Command::Verify(path) => verify(path),
+            Command::Merge(paths) => merge(paths, io::stdout().lock()),
+        }
+    }
 }
 
-#[test]
-fn test_dispute_chargeback_withdrawal() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,5.0
-deposit,1,2,3.0
-deposit,1,3,4.0
-withdrawal,1,4,8.0
-dispute,1,4,
-dispute,1,2,
-chargeback,1,4,
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, 9.into());
-    assert_eq!(client.held, 3.into());
-    assert_eq!(client.locked, true);
+fn main() {
+    // Rejected rows are logged as warnings, RUST_LOG=error hides them
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let mut args = env::args();
+    let program = args.next().unwrap_or_else(|| "transactions".to_string());
+    let command = match Command::parse(args) {
+        Ok(command) => command,
+        Err(e) => {
+            log::error!("{}", e);
+            println!("Usage: {} {}", program, USAGE);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = command.run() {
+        log::error!("{}", e);
+        std::process::exit(1)
+    }
 }
 
-#[test]
-fn test_incorrect_ops() {
-    let input = r#"type,client,tx,amount
-deposits,1,1,5.0
-withdraw,2,2,7.0
-withdrawx,3,3,5.1
-disputer,4,4,5.5
-resolv,4,4,5.5
-charge-back,4,4,5.5
-,,,
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 0);
+#[cfg(test)]
+fn test_snapshot_file(name: &str, input: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("transactions-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let csv = dir.join(format!("{}.csv", name));
+    std::fs::write(&csv, input).unwrap();
+    let path = dir.join(format!("{}.json", name));
+    let args = vec!["snapshot", path.to_str().unwrap(), csv.to_str().unwrap()];
+    let command = Command::parse(args.into_iter().map(String::from)).unwrap();
+    command.run().unwrap();
+    path
 }
 
 #[test]
-fn test_undisputed() {
-    let input = r#"type,client,tx,amount
-deposit,1,1,1.0
-resolve,1,2,1.0
-chargeback,2,3,1.0
-resolve,2,4,
-chargeback,2,5,
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, 1.into());
-    assert_eq!(client.locked, false);
+fn test_verify_command() {
+    let path = test_snapshot_file(
+        "verify",
+        "type,client,tx,amount\ndeposit,1,1,5\ndeposit,1,2,3\ndispute,1,1,\n",
+    );
+    let args = vec!["verify".to_string(), path.to_str().unwrap().to_string()];
+    assert!(Command::parse(args.into_iter()).unwrap().run().is_ok());
+
+    // Balances no longer match the deposits
+    let tampered = std::fs::read_to_string(&path)
+        .unwrap()
+        .replace(r#""available":"3""#, r#""available":"4""#);
+    std::fs::write(&path, tampered).unwrap();
+    let err = verify(&path).unwrap_err();
+    assert!(err.contains("clients [1]"), "{}", err);
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
 }
 
 #[test]
-fn test_withdraw_deposit() {
-    let input = r#"type,client,tx,amount
-withdrawal,1,1,5.01
-deposit,1,2,7.01
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, Decimal::from_str("7.01").unwrap());
-    assert_eq!(client.held, 0.into());
-    assert_eq!(client.locked, false);
+fn test_merge_command() {
+    let first = test_snapshot_file("first", "type,client,tx,amount\ndeposit,1,1,5\n");
+    let second = test_snapshot_file("second", "type,client,tx,amount\ndeposit,2,2,3\n");
+    let args = vec!["merge", first.to_str().unwrap(), second.to_str().unwrap()];
+    assert!(matches!(
+        Command::parse(args.into_iter().map(String::from)),
+        Ok(Command::Merge(paths)) if paths == vec![first.clone(), second.clone()]
+    ));
+    let mut output = Vec::new();
+    merge(&[first.clone(), second.clone()], &mut output).unwrap();
+    for path in [first, second] {
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+    let merged = Snapshot::read_json(output.as_slice()).unwrap();
+    let mut engine = TransactionEngine::new();
+    engine.apply_delta(&merged);
+    assert_eq!(engine.held(1), Some(0.into()));
+    assert_eq!(engine.held(2), Some(0.into()));
+    assert_eq!(engine.grand_total(), 8.into());
+    assert!(engine.verify_integrity().is_ok());
 }
 
 #[test]
-fn test_dispute_not_applied() {
-    let input = r#"type,client,tx,amount
-withdrawal,1,1,5.01
-deposit,1,2,7.01
-dispute,1,1,
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, Decimal::from_str("7.01").unwrap());
-    assert_eq!(client.held, 0.into());
-    assert_eq!(client.locked, false);
+fn test_no_clobber() {
+    let dir = env::temp_dir().join(format!("transactions-no-clobber-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let csv = dir.join("input.csv");
+    std::fs::write(&csv, "type,client,tx,amount\ndeposit,1,1,5\n").unwrap();
+    let output = dir.join("output.csv");
+    let args = vec![
+        "--no-clobber",
+        "--output",
+        output.to_str().unwrap(),
+        csv.to_str().unwrap(),
+    ];
+    let command = Command::parse(args.clone().into_iter().map(String::from)).unwrap();
+
+    // The output doesn't exist yet
+    command.run().unwrap();
+    let written = std::fs::read_to_string(&output).unwrap();
+    assert!(written.starts_with("client,available,held,total,locked\n1,"));
+
+    let err = command.run().unwrap_err();
+    assert!(err.contains("already exists"), "{}", err);
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), written);
+
+    // Failed processing doesn't leave an empty file blocking a retry
+    std::fs::remove_file(&output).unwrap();
+    let mut failing = args.clone();
+    failing.extend(["--assert-balanced", "100"]);
+    let command = Command::parse(failing.into_iter().map(String::from)).unwrap();
+    let err = command.run().unwrap_err();
+    assert!(err.contains("doesn't match"), "{}", err);
+    assert!(!output.exists());
+    let command = Command::parse(args.into_iter().map(String::from)).unwrap();
+    command.run().unwrap();
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), written);
+
+    // Without --no-clobber, failed processing leaves the previous output intact
+    let args = vec![
+        "--output",
+        output.to_str().unwrap(),
+        "--assert-balanced",
+        "100",
+        csv.to_str().unwrap(),
+    ];
+    let command = Command::parse(args.into_iter().map(String::from)).unwrap();
+    let err = command.run().unwrap_err();
+    assert!(err.contains("doesn't match"), "{}", err);
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), written);
+    let mut files: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["input.csv", "output.csv"]);
+    std::fs::remove_dir_all(dir).unwrap();
 }
 
 #[test]
-fn test_dispute_not_applied_client_exists() {
-    let input = r#"type,client,tx,amount
-deposit,1,4,3.0013
-withdrawal,1,1,5.01
-deposit,1,2,7.01
-dispute,1,1,
-"#;
-    let clients = test_clients(&input);
-    assert_eq!(clients.len(), 1);
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.available, Decimal::from_str("10.0113").unwrap());
-    assert_eq!(client.held, 0.into());
-    assert_eq!(client.locked, false);
+fn test_benchmark() {
+    assert_eq!(
+        throughput(3000, Duration::from_millis(1500)),
+        "Processed 3000 rows in 1.500s, 2000 rows/s"
+    );
+    assert_eq!(
+        throughput(0, Duration::from_secs(0)),
+        "Processed 0 rows in 0.000s, 0 rows/s"
+    );
+
+    let dir = env::temp_dir().join(format!("transactions-benchmark-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let csv = dir.join("input.csv");
+    std::fs::write(
+        &csv,
+        "type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2,2\n",
+    )
+    .unwrap();
+    let output = dir.join("output.csv");
+    let args = vec![
+        "--benchmark",
+        "--output",
+        output.to_str().unwrap(),
+        csv.to_str().unwrap(),
+    ];
+    let command = Command::parse(args.into_iter().map(String::from)).unwrap();
+    command.run().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&output).unwrap(),
+        "client,available,held,total,locked\n1,3,0,3,false\n"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
 }